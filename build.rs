@@ -3,6 +3,8 @@ extern crate winres;
 
 #[cfg(windows)]
 fn main() {
+	emit_git_hash();
+
 	let mut res = winres::WindowsResource::new();
 	res.set_icon("windows-icon.ico");
 	res.compile().unwrap();
@@ -10,5 +12,27 @@ fn main() {
 
 #[cfg(unix)]
 fn main() {
-	// Nothing special to do here for the time being
+	emit_git_hash();
+}
+
+/// Exposes the hash of the commit being built (if it's being built from a git repo) as HEARTSOCK_GIT_HASH
+fn emit_git_hash() {
+	// Rebuild whenever the checked out commit changes (HEAD changes when switching branches, and the refs change when
+	// committing to the current branch). Paths that don't exist are left out, since cargo would rerun this every build.
+	for path in [".git/HEAD", ".git/refs/heads", ".git/packed-refs"] {
+		if std::path::Path::new(path).exists() {
+			println!("cargo:rerun-if-changed={}", path);
+		}
+	}
+
+	let output = std::process::Command::new("git")
+		.args(["rev-parse", "--short", "HEAD"])
+		.output();
+
+	if let Ok(output) = output {
+		if output.status.success() {
+			let hash = String::from_utf8_lossy(&output.stdout);
+			println!("cargo:rustc-env=HEARTSOCK_GIT_HASH={}", hash.trim());
+		}
+	}
 }
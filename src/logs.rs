@@ -0,0 +1,169 @@
+use crate::websocket::{Message, SessionID};
+use ezsockets::Session;
+use std::{
+	collections::HashMap,
+	fmt::{Debug, Write},
+	sync::{Arc, Mutex},
+};
+use tracing::{
+	field::{Field, Visit},
+	metadata::LevelFilter,
+	Event, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+	filter::{filter_fn, FilterFn},
+	layer::Context,
+	reload, Layer, Registry,
+};
+
+/// Handle for changing the max level of logs that are output at runtime
+pub type LevelHandle = reload::Handle<LevelFilter, Registry>;
+
+/// Targets whose events are never forwarded, since sending a forwarded line emits events from them (which would then
+/// be forwarded again)
+const IGNORED_TARGETS: [&str; 4] = ["ezsockets", "tokio_tungstenite", "tungstenite", module_path!()];
+
+/// Tracing layer that forwards log lines to sessions that are tailing the logs
+#[derive(Clone, Default)]
+pub struct LogForwarder {
+	/// Sessions currently tailing the logs
+	tails: Arc<Mutex<HashMap<SessionID, Tail>>>,
+}
+
+/// Session that is tailing the logs
+struct Tail {
+	/// Max level of log lines the session wants
+	level: LevelFilter,
+	/// Handle to send log lines to
+	session: Session<SessionID, Message>,
+}
+
+impl LogForwarder {
+	/// Starts forwarding log lines at or above a level to a session
+	pub fn tail(&self, session: Session<SessionID, Message>, level: LevelFilter) {
+		self.tails.lock().unwrap().insert(session.id, Tail { level, session });
+	}
+
+	/// Stops forwarding log lines to a session, returning whether it was tailing the logs
+	pub fn stop(&self, id: &SessionID) -> bool {
+		self.tails.lock().unwrap().remove(id).is_some()
+	}
+
+	/// Creates a filter that only lets through events that at least one session is interested in
+	pub fn filter(&self) -> FilterFn<impl Fn(&Metadata<'_>) -> bool> {
+		let forwarder = self.clone();
+		filter_fn(move |metadata| forwarder.wants(metadata))
+	}
+
+	/// Checks whether any tailing session wants an event with the given metadata
+	fn wants(&self, metadata: &Metadata<'_>) -> bool {
+		if IGNORED_TARGETS
+			.iter()
+			.any(|target| metadata.target().starts_with(target))
+		{
+			return false;
+		}
+
+		self.tails
+			.lock()
+			.unwrap()
+			.values()
+			.any(|tail| tail.level >= *metadata.level())
+	}
+}
+
+impl<S: Subscriber> Layer<S> for LogForwarder {
+	fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+		let metadata = event.metadata();
+
+		// Format the event as a single line
+		let mut line = LineVisitor(format!("log: {} {}:", metadata.level(), metadata.target()));
+		event.record(&mut line);
+
+		// Send the line to all sessions that want it
+		let tails = self.tails.lock().unwrap();
+		let tails = tails
+			.values()
+			.filter(|tail| tail.level >= *metadata.level() && tail.session.alive());
+		for tail in tails {
+			tail.session.text(line.0.clone());
+		}
+	}
+}
+
+/// Field visitor that appends all of an event's fields to a line of text
+struct LineVisitor(String);
+
+impl Visit for LineVisitor {
+	fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+		// Writing to a String can't fail
+		let _ = match field.name() {
+			"message" => write!(self.0, " {:?}", value),
+			name => write!(self.0, " {}={:?}", name, value),
+		};
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::websocket::{
+		harness::{test_server_with_logs, TestClient},
+		ServerConfig,
+	};
+	use tracing_subscriber::layer::SubscriberExt;
+
+	#[tokio::test]
+	async fn tail_forwards_later_log_lines() {
+		let logs = LogForwarder::default();
+		let subscriber = Registry::default().with(logs.clone().with_filter(logs.filter()));
+		let _guard = tracing::subscriber::set_default(subscriber);
+
+		let server = test_server_with_logs(
+			ServerConfig {
+				admin_token: Some("admin".to_owned()),
+				..Default::default()
+			},
+			logs,
+		);
+		let mut admin = TestClient::connect(&server).await;
+		assert_eq!(admin.cmd("logs tail").await, "error: not authorized");
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(admin.cmd("logs tail").await, "ok");
+
+		tracing::info!(target: "heartsock_server::test", "Something happened");
+		assert_eq!(
+			admin.recv().await,
+			"log: INFO heartsock_server::test: Something happened"
+		);
+
+		assert_eq!(admin.cmd("logs stop").await, "ok");
+		tracing::info!(target: "heartsock_server::test", "Something else happened");
+		assert_eq!(admin.cmd("ping").await, "pong");
+	}
+
+	#[tokio::test]
+	async fn tail_filters_by_level() {
+		let logs = LogForwarder::default();
+		let subscriber = Registry::default().with(logs.clone().with_filter(logs.filter()));
+		let _guard = tracing::subscriber::set_default(subscriber);
+
+		let server = test_server_with_logs(
+			ServerConfig {
+				admin_token: Some("admin".to_owned()),
+				..Default::default()
+			},
+			logs,
+		);
+		let mut admin = TestClient::connect(&server).await;
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(admin.cmd("logs tail warn").await, "ok");
+
+		tracing::info!(target: "heartsock_server::test", "Not important");
+		tracing::warn!(target: "heartsock_server::test", count = 3, "Important");
+		assert_eq!(
+			admin.recv().await,
+			"log: WARN heartsock_server::test: Important count=3"
+		);
+	}
+}
@@ -1,10 +1,17 @@
 use anyhow::{anyhow, Context, Result};
-use clap::{arg, command, Parser};
-use std::net::SocketAddr;
+use clap::Parser;
+use logs::LogForwarder;
+use std::{collections::HashSet, io, net::SocketAddr, path::Path, str::FromStr, time::Duration};
 use tokio::fs;
 use tracing::metadata::LevelFilter;
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, layer::SubscriberExt, reload, util::SubscriberInitExt, Layer};
+use websocket::{DataFormat, ServerConfig, Value};
 
+mod logs;
+#[cfg(any(feature = "simple-mdns", feature = "mdns-sd"))]
 mod mdns;
+#[cfg(feature = "simulate")]
+mod simulate;
 mod websocket;
 
 #[derive(Parser, Debug)]
@@ -14,49 +21,283 @@ struct Args {
 	#[arg(short, long, default_value_t = SocketAddr::from(([0, 0, 0, 0], 9001)))]
 	listen: SocketAddr,
 
+	/// Number of following ports to try listening on if the listen port is already in use
+	#[arg(long)]
+	port_fallback: Option<u16>,
+
+	/// Additional socket address to listen on for read-only clients, which can never set values
+	#[arg(long)]
+	readonly_addr: Option<SocketAddr>,
+
 	/// Disables mDNS advertisement
 	#[cfg(any(feature = "simple-mdns", feature = "mdns-sd"))]
 	#[arg(short, long)]
 	disable_mdns: bool,
 
-	/// IP to advertise (via mDNS) for connecting to
-	#[cfg(feature = "simple-mdns")]
+	/// IP to advertise (via mDNS) for connecting to (defaults to the listen IP if it's a specific one, or else the detected
+	/// local IP)
+	#[cfg(any(feature = "simple-mdns", feature = "mdns-sd"))]
 	#[arg(short, long)]
 	advertise_ip: Option<std::net::IpAddr>,
 
-	/// IP to advertise (via mDNS) for connecting to
-	#[cfg(feature = "mdns-sd")]
-	#[arg(short, long)]
-	advertise_ip: Option<std::net::Ipv4Addr>,
+	/// mDNS implementation to advertise with
+	#[cfg(any(feature = "simple-mdns", feature = "mdns-sd"))]
+	#[arg(long, value_enum, default_value_t = mdns::MdnsBackend::default())]
+	mdns_backend: mdns::MdnsBackend,
 
-	/// Directory to write plain text files in for each value type (bpm, battery, tracker)
+	/// Directory to write plain text files in for each value type (bpm, battery, tracker) and/or a state.json file
 	#[arg(short = 'D', long)]
 	data_dir: Option<std::path::PathBuf>,
 
+	/// Which files to write to the data directory
+	#[arg(long, value_enum, default_value_t = DataFormat::Text, requires = "data_dir")]
+	data_format: DataFormat,
+
+	/// Seed the values from the files in the data directory on startup, to preserve them across restarts
+	#[arg(long, requires = "data_dir")]
+	restore_from_data_dir: bool,
+
 	/// Max log level to output
 	#[arg(short = 'o', long, default_value_t = LevelFilter::INFO)]
 	log_level: LevelFilter,
+
+	/// Token that clients can authenticate with (via "auth <token>") to use admin commands
+	#[arg(short = 't', long)]
+	admin_token: Option<String>,
+
+	/// Token that clients can authenticate with to set only certain values, as token=keys with comma-separated keys
+	/// (e.g. "s3cret=battery"). If any are given, clients can only set the values their tokens permit.
+	#[arg(long, value_parser = parse_acl)]
+	acl: Vec<(String, HashSet<String>)>,
+
+	/// Prefix each response with the command it's for (clients can toggle this with "echo on"/"echo off")
+	#[arg(long)]
+	echo_commands: bool,
+
+	/// Require clients to send "claim" to become the tracker instead of promoting the first client to set a value
+	#[arg(long)]
+	explicit_tracker: bool,
+
+	/// Minimum time between broadcasts of changes to a value, as comma-separated key=duration pairs
+	/// (e.g. "bpm=0,battery=5s")
+	#[arg(long, value_delimiter = ',', value_parser = parse_key_duration)]
+	broadcast_interval: Vec<(String, Duration)>,
+
+	/// Report values that have never been set as "unset" instead of 0
+	#[arg(long)]
+	distinguish_unset: bool,
+
+	/// How often to rewrite all files in the data directory, even if their values haven't changed (e.g. "30s")
+	#[arg(long, value_parser = parse_duration, requires = "data_dir")]
+	data_flush_interval: Option<Duration>,
+
+	/// Batch writes to the data directory instead of writing on every change, writing only the latest value of each
+	/// changed key at most this often (e.g. "1s") and once more on shutdown
+	#[arg(long, value_parser = parse_duration, requires = "data_dir")]
+	data_write_interval: Option<Duration>,
+
+	/// Headers from clients' WebSocket upgrade requests to log and show in "info", as a comma-separated list
+	#[arg(long, value_delimiter = ',', default_value = "user-agent")]
+	capture_headers: Vec<String>,
+
+	/// Text to report in place of values that trackers set while there's no tracker (e.g. "--")
+	#[arg(long)]
+	no_tracker_placeholder: Option<String>,
+
+	/// Key that "set <value>" and "get" are for when no key is given (e.g. "bpm")
+	#[arg(long, value_parser = parse_value_key)]
+	default_key: Option<String>,
+
+	/// Write a JSON Lines record of each value change to stdout (logs are written to stderr instead)
+	#[arg(long)]
+	stdout_jsonl: bool,
+
+	/// Max number of sessions that clients from a single IP can have at once
+	#[arg(long)]
+	max_sessions_per_ip: Option<usize>,
+
+	/// Max number of sessions the server can have at once, across all clients
+	#[arg(long)]
+	max_sessions: Option<usize>,
+
+	/// Max number of commands per second that browser clients from a single origin (by their Origin header) can send
+	/// across all of their connections, so one site embedding the server can't starve others
+	#[arg(long)]
+	origin_rate_limit: Option<u32>,
+
+	/// Send each client its session ID ("id: <n>") as soon as it connects, before the current values
+	#[arg(long)]
+	send_id_on_connect: bool,
+
+	/// Match value keys and other command arguments case-sensitively (command names are always case-insensitive)
+	#[arg(long)]
+	case_sensitive_args: bool,
+
+	/// Don't send clients the current values when they connect (they can request them with "sync" instead)
+	#[arg(long)]
+	no_snapshot_on_connect: bool,
+
+	/// Show clients random opaque session IDs instead of sequential numbers (logs include both)
+	#[arg(long)]
+	opaque_session_ids: bool,
+
+	/// How often to send all clients a "keepalive <timestamp>" line, so they can tell the server is alive while no
+	/// values are changing (e.g. "15s")
+	#[arg(long, value_parser = parse_duration)]
+	keepalive_broadcast: Option<Duration>,
+
+	/// How long a value trackers set can go without an update before the "stale" command reports it (e.g. "10s") -
+	/// without this, values are only stale while there's no tracker
+	#[arg(long, value_parser = parse_duration)]
+	stale_after: Option<Duration>,
+
+	/// Largest change to a value that a single set can make, as comma-separated key=delta pairs (e.g. "bpm=40") -
+	/// sets that change a value by more are rejected as implausible
+	#[arg(long, value_delimiter = ',', value_parser = parse_key_delta)]
+	max_delta: Vec<(String, Value)>,
+
+	/// Values to report smoothed to clients instead of raw (data files keep the raw values), as comma-separated
+	/// key=ema:<factor> pairs, where the factor is between 0 and 1 and lower is smoother (e.g. "bpm=ema:0.3")
+	#[arg(long, value_delimiter = ',', value_parser = parse_key_smoothing)]
+	smooth: Vec<(String, f64)>,
+
+	/// Add a sequence number to each value line sent to clients (e.g. "bpm: 120 #42"), incremented whenever the value
+	/// changes, so clients can detect missed changes and resync
+	#[arg(long)]
+	sequence_numbers: bool,
+
+	/// Pin the tracker role to the IP of the first client to become the tracker, rejecting sets and claims from any
+	/// other IP for as long as the server runs
+	#[arg(long)]
+	pin_tracker_ip: bool,
+
+	/// Combine changes to several values that are broadcast on the same interval into a single "update key=value ..."
+	/// line instead of a line for each
+	#[arg(long)]
+	combine_broadcasts: bool,
+
+	/// Append the ID of the client that set each value to the lines notifying other clients of it (e.g. "bpm: 120
+	/// from=3")
+	#[arg(long)]
+	broadcast_source: bool,
+
+	/// File to append a JSON Lines audit record of every set/cas command to, including whether it was accepted
+	#[arg(long)]
+	audit_log: Option<std::path::PathBuf>,
+
+	/// File to watch for values written by other programs, as key=value lines (e.g. "bpm=120"), which are applied
+	/// whenever it changes
+	#[arg(long)]
+	watch_file: Option<std::path::PathBuf>,
+
+	/// Accept the old protocol's command shapes for clients that haven't been updated: a bare number sets the heart
+	/// rate, and "get" responds with just the value (e.g. "120" instead of "bpm: 120")
+	#[arg(long)]
+	legacy_protocol: bool,
+
+	/// Let the tracker know when the last other client disconnects ("no-observers") and when one connects again
+	/// ("observers-present"), so it can save power while no one is watching
+	#[arg(long)]
+	observer_events: bool,
+
+	/// Reject sets with a client timestamp ("set bpm 110 ts=<unix millis>") older than the latest one accepted for the
+	/// key, so readings a tracker buffered and flushed late can't move values backward in time
+	#[arg(long)]
+	monotonic_timestamps: bool,
+
+	/// Longest to wait on Ctrl+C for buffered data to be written and sessions to be closed before exiting anyway
+	#[arg(long, value_parser = parse_duration, default_value = "10s")]
+	shutdown_timeout: Duration,
+
+	/// Reject commands containing anything other than printable ASCII characters and spaces (e.g. control characters or
+	/// NULs) with an error instead of trying to handle them
+	#[arg(long)]
+	ascii_commands: bool,
+
+	/// Close a client's older connection when it connects again with the same device ID ("device-id <id>"), e.g. when a
+	/// tracker reconnects before its old connection times out (duplicates are logged either way)
+	#[arg(long)]
+	dedupe_devices: bool,
+
+	/// Run a simulated tracker that sends a live-looking heart rate and a draining battery, for demos without a real
+	/// heart rate monitor
+	#[cfg(feature = "simulate")]
+	#[arg(long)]
+	simulate: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
 	let args = Args::parse();
 
-	// Set up tracing
-	tracing_subscriber::fmt().with_max_level(args.log_level).init();
+	// Make sure the listeners won't try to bind the same socket
+	if let Some(readonly_addr) = args.readonly_addr {
+		if addrs_collide(args.listen, readonly_addr) {
+			return Err(anyhow!(
+				"The read-only address ({}) collides with the listen address ({}) - use a different port",
+				readonly_addr,
+				args.listen
+			));
+		}
+	}
+
+	// The simulated tracker has to authenticate to set values if there are access control tokens, preferring a token
+	// that only permits the keys it sets over the admin token
+	#[cfg(feature = "simulate")]
+	let simulate_token = if args.simulate && !args.acl.is_empty() {
+		let token = args
+			.acl
+			.iter()
+			.find(|(_, keys)| keys.contains(websocket::KEY_BPM) && keys.contains(websocket::KEY_BATTERY))
+			.map(|(token, _)| token.clone())
+			.or_else(|| args.admin_token.clone());
+		match token {
+			Some(token) => Some(token),
+			None => {
+				return Err(anyhow!(
+					"The simulated tracker needs an --acl token that permits bpm and battery (or an --admin-token) to \
+					 authenticate with"
+				))
+			}
+		}
+	} else {
+		None
+	};
+
+	// Set up tracing, forwarding logs to any sessions that are tailing them (stdout is kept clear for value changes if
+	// they're being written there)
+	let logs = LogForwarder::default();
+	let log_writer = if args.stdout_jsonl {
+		BoxMakeWriter::new(io::stderr)
+	} else {
+		BoxMakeWriter::new(io::stdout)
+	};
+	let (log_level, log_level_handle) = reload::Layer::new(args.log_level);
+	tracing_subscriber::registry()
+		.with(
+			tracing_subscriber::fmt::layer()
+				.with_writer(log_writer)
+				.with_filter(log_level),
+		)
+		.with(logs.clone().with_filter(logs.filter()))
+		.init();
 
 	// Create the data directory if it doesn't exist
 	if let Some(data_dir) = &args.data_dir {
-		fs::create_dir_all(data_dir)
-			.await
-			.context("Failed to create data directory")?;
+		create_data_dir(data_dir).await?;
 	}
 
+	// Find a free port to listen on if the requested one is taken (if enabled)
+	let listen = match args.port_fallback {
+		Some(tries) => find_free_addr(args.listen, tries, args.readonly_addr)?,
+		None => args.listen,
+	};
+
 	// Advertise the server via MDNS
 	cfg_if::cfg_if! {
 		if #[cfg(any(feature = "simple-mdns", feature = "mdns-sd"))] {
 			if !args.disable_mdns {
-				mdns::advertise(args.listen.port(), args.advertise_ip)
+				mdns::advertise(args.mdns_backend, listen, args.advertise_ip)
 					.await
 					.unwrap_or_else(|err| tracing::error!("Unable to advertise via mDNS: {}", err));
 			}
@@ -64,8 +305,345 @@ async fn main() -> Result<()> {
 	}
 
 	// Run the server
-	websocket::run(args.listen, args.data_dir)
+	cfg_if::cfg_if! {
+		if #[cfg(any(feature = "simple-mdns", feature = "mdns-sd"))] {
+			let mdns_backend = (!args.disable_mdns)
+				.then(|| clap::ValueEnum::to_possible_value(&args.mdns_backend))
+				.flatten()
+				.map(|backend| backend.get_name().to_owned());
+		} else {
+			let mdns_backend = None;
+		}
+	}
+	let config = ServerConfig {
+		data_dir: args.data_dir,
+		data_format: args.data_format,
+		admin_token: args.admin_token,
+		acl: args.acl.into_iter().collect(),
+		echo_commands: args.echo_commands,
+		explicit_tracker: args.explicit_tracker,
+		broadcast_intervals: args
+			.broadcast_interval
+			.into_iter()
+			.filter(|(_, interval)| !interval.is_zero())
+			.collect(),
+		distinguish_unset: args.distinguish_unset,
+		data_flush_interval: args.data_flush_interval.filter(|interval| !interval.is_zero()),
+		data_write_interval: args.data_write_interval.filter(|interval| !interval.is_zero()),
+		capture_headers: args
+			.capture_headers
+			.into_iter()
+			.filter(|name| !name.is_empty())
+			.map(|name| name.to_lowercase())
+			.collect(),
+		no_tracker_placeholder: args.no_tracker_placeholder,
+		default_key: args.default_key,
+		restore_from_data_dir: args.restore_from_data_dir,
+		stdout_jsonl: args.stdout_jsonl,
+		max_sessions_per_ip: args.max_sessions_per_ip,
+		max_sessions: args.max_sessions,
+		origin_rate_limit: args.origin_rate_limit,
+		send_id_on_connect: args.send_id_on_connect,
+		case_sensitive_args: args.case_sensitive_args,
+		skip_snapshot_on_connect: args.no_snapshot_on_connect,
+		opaque_session_ids: args.opaque_session_ids,
+		log_level: Some(log_level_handle),
+		keepalive_interval: args.keepalive_broadcast.filter(|interval| !interval.is_zero()),
+		stale_after: args.stale_after,
+		max_deltas: args.max_delta.into_iter().collect(),
+		smoothing: args.smooth.into_iter().collect(),
+		mdns_backend,
+		sequence_numbers: args.sequence_numbers,
+		pin_tracker_ip: args.pin_tracker_ip,
+		combine_broadcasts: args.combine_broadcasts,
+		broadcast_source: args.broadcast_source,
+		audit_log: args.audit_log,
+		watch_file: args.watch_file,
+		legacy_protocol: args.legacy_protocol,
+		observer_events: args.observer_events,
+		monotonic_timestamps: args.monotonic_timestamps,
+		shutdown_timeout: args.shutdown_timeout,
+		ascii_commands: args.ascii_commands,
+		dedupe_devices: args.dedupe_devices,
+	};
+	#[cfg(feature = "simulate")]
+	if args.simulate {
+		tokio::spawn(async move {
+			if let Err(err) = simulate::run(listen, simulate_token).await {
+				tracing::error!("Simulated tracker stopped: {}", err);
+			}
+		});
+	}
+	websocket::run(listen, args.readonly_addr, config, logs)
 		.await
 		.map_err(|err| anyhow!(err))
-		.with_context(|| format!("Failed to run WebSocket server on {}", args.listen))
+		.with_context(|| format!("Failed to run WebSocket server on {}", listen))
+}
+
+/// Creates the data directory (and its parents) if it doesn't exist, with a clear error if the path is something other
+/// than a directory
+async fn create_data_dir(data_dir: &Path) -> Result<()> {
+	if data_dir.exists() && !data_dir.is_dir() {
+		return Err(anyhow!(
+			"Data directory path ({}) exists and is not a directory",
+			data_dir.display()
+		));
+	}
+	fs::create_dir_all(data_dir)
+		.await
+		.context("Failed to create data directory")
+}
+
+/// Finds the first address starting from the given one (trying up to a number of following ports) that's free to listen
+/// on, skipping any that would collide with the read-only address. The port is only probed, so it's still possible
+/// (though unlikely) for something else to take it before the server binds it.
+fn find_free_addr(addr: SocketAddr, tries: u16, readonly_addr: Option<SocketAddr>) -> Result<SocketAddr> {
+	for offset in 0..=tries {
+		let candidate = match addr.port().checked_add(offset) {
+			Some(port) => SocketAddr::new(addr.ip(), port),
+			None => break,
+		};
+		if readonly_addr.is_some_and(|readonly_addr| addrs_collide(candidate, readonly_addr)) {
+			continue;
+		}
+
+		match std::net::TcpListener::bind(candidate) {
+			Ok(..) => {
+				if offset > 0 {
+					tracing::warn!(
+						"Port {} is in use - listening on port {} instead",
+						addr.port(),
+						candidate.port()
+					);
+				}
+				return Ok(candidate);
+			}
+			Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+				tracing::debug!("Port {} is in use", candidate.port());
+			}
+			Err(err) => return Err(err).with_context(|| format!("Failed to bind {}", candidate)),
+		}
+	}
+
+	Err(anyhow!(
+		"Port {} and the {} ports after it are all in use",
+		addr.port(),
+		tries
+	))
+}
+
+/// Checks whether two socket addresses would conflict when binding both (same port, and the same IP or either IP is
+/// unspecified)
+fn addrs_collide(a: SocketAddr, b: SocketAddr) -> bool {
+	a.port() == b.port() && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
+
+/// Parses the key of a value that trackers can set
+fn parse_value_key(arg: &str) -> Result<String, String> {
+	websocket::value_spec(&arg.to_lowercase())
+		.map(|spec| spec.key.to_owned())
+		.ok_or_else(|| format!("unknown value key \"{}\"", arg))
+}
+
+/// Parses a token=keys access control entry
+fn parse_acl(arg: &str) -> Result<(String, HashSet<String>), String> {
+	let (token, keys) = arg
+		.rsplit_once('=')
+		.filter(|(token, _)| !token.is_empty())
+		.ok_or_else(|| format!("expected token=keys, got \"{}\"", arg))?;
+	let keys = keys.split(',').map(parse_value_key).collect::<Result<_, _>>()?;
+	Ok((token.to_owned(), keys))
+}
+
+/// Parses a key=delta pair
+fn parse_key_delta(arg: &str) -> Result<(String, Value), String> {
+	let (key, delta) = arg
+		.split_once('=')
+		.ok_or_else(|| format!("expected key=delta, got \"{}\"", arg))?;
+	let delta = delta.parse().map_err(|_| format!("invalid delta \"{}\"", delta))?;
+	Ok((parse_value_key(key)?, delta))
+}
+
+/// Parses a key=ema:<factor> pair
+fn parse_key_smoothing(arg: &str) -> Result<(String, f64), String> {
+	let (key, smoothing) = arg
+		.split_once('=')
+		.ok_or_else(|| format!("expected key=ema:<factor>, got \"{}\"", arg))?;
+	let alpha = smoothing
+		.strip_prefix("ema:")
+		.ok_or_else(|| format!("unknown smoothing \"{}\" (only ema:<factor> is supported)", smoothing))?;
+	match alpha.parse::<f64>() {
+		Ok(alpha) if alpha > 0.0 && alpha <= 1.0 => Ok((parse_value_key(key)?, alpha)),
+		_ => Err(format!(
+			"invalid smoothing factor \"{}\" (must be above 0 and at most 1)",
+			alpha
+		)),
+	}
+}
+
+/// Parses a key=duration pair
+fn parse_key_duration(arg: &str) -> Result<(String, Duration), String> {
+	let (key, val) = arg
+		.split_once('=')
+		.ok_or_else(|| format!("expected key=duration, got \"{}\"", arg))?;
+	Ok((parse_value_key(key)?, parse_duration(val)?))
+}
+
+/// Parses a duration with an optional unit (ms, s, m, or h), defaulting to seconds
+fn parse_duration(arg: &str) -> Result<Duration, String> {
+	let split = arg.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(arg.len());
+	let (amount, unit) = arg.split_at(split);
+	let amount = f64::from_str(amount).map_err(|_| format!("invalid duration \"{}\"", arg))?;
+	let secs = match unit {
+		"ms" => amount / 1000.0,
+		"" | "s" => amount,
+		"m" => amount * 60.0,
+		"h" => amount * 3600.0,
+		_ => return Err(format!("unknown duration unit \"{}\"", unit)),
+	};
+	Duration::try_from_secs_f64(secs).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_duration_defaults_to_seconds() {
+		assert_eq!(parse_duration("15"), Ok(Duration::from_secs(15)));
+		assert_eq!(parse_duration("1.5"), Ok(Duration::from_millis(1500)));
+	}
+
+	#[test]
+	fn parse_duration_accepts_units() {
+		assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+		assert_eq!(parse_duration("10s"), Ok(Duration::from_secs(10)));
+		assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+		assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+	}
+
+	#[test]
+	fn parse_duration_rejects_invalid_input() {
+		assert_eq!(parse_duration("5d"), Err("unknown duration unit \"d\"".to_owned()));
+		assert_eq!(parse_duration("s"), Err("invalid duration \"s\"".to_owned()));
+		assert_eq!(parse_duration(""), Err("invalid duration \"\"".to_owned()));
+		assert!(parse_duration("-5s").is_err());
+	}
+
+	#[test]
+	fn broadcast_interval_rejects_unknown_keys() {
+		let args = Args::try_parse_from(["heartsock-server", "--broadcast-interval", "bpm=0,Battery=5s"]).unwrap();
+		assert_eq!(
+			args.broadcast_interval,
+			vec![
+				("bpm".to_owned(), Duration::ZERO),
+				("battery".to_owned(), Duration::from_secs(5))
+			]
+		);
+		assert!(Args::try_parse_from(["heartsock-server", "--broadcast-interval", "bmp=5s"]).is_err());
+	}
+
+	#[test]
+	fn default_key_must_be_settable() {
+		let args = Args::try_parse_from(["heartsock-server", "--default-key", "BPM"]).unwrap();
+		assert_eq!(args.default_key, Some("bpm".to_owned()));
+		assert!(Args::try_parse_from(["heartsock-server", "--default-key", "tracker"]).is_err());
+	}
+
+	#[test]
+	fn addrs_collide_on_same_port_and_ip() {
+		let a: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+		assert!(addrs_collide(a, a));
+		assert!(!addrs_collide(a, "127.0.0.1:9002".parse().unwrap()));
+		assert!(!addrs_collide(a, "127.0.0.2:9001".parse().unwrap()));
+	}
+
+	#[test]
+	fn addrs_collide_with_unspecified_ip_on_same_port() {
+		let any: SocketAddr = "0.0.0.0:9001".parse().unwrap();
+		assert!(addrs_collide(any, "127.0.0.1:9001".parse().unwrap()));
+		assert!(addrs_collide("127.0.0.1:9001".parse().unwrap(), any));
+		assert!(!addrs_collide(any, "127.0.0.1:9002".parse().unwrap()));
+	}
+
+	#[test]
+	fn acl_entries_must_have_a_token_and_settable_keys() {
+		let args =
+			Args::try_parse_from(["heartsock-server", "--acl", "secret=bpm,Battery", "--acl", "a=b=bpm"]).unwrap();
+		assert_eq!(
+			args.acl,
+			vec![
+				(
+					"secret".to_owned(),
+					HashSet::from(["bpm".to_owned(), "battery".to_owned()])
+				),
+				("a=b".to_owned(), HashSet::from(["bpm".to_owned()]))
+			]
+		);
+		for acl in ["secret", "=bpm", "secret=bpm,nope"] {
+			assert!(Args::try_parse_from(["heartsock-server", "--acl", acl]).is_err());
+		}
+	}
+
+	#[test]
+	fn parse_key_smoothing_parses_ema_factor() {
+		assert_eq!(parse_key_smoothing("bpm=ema:0.3"), Ok(("bpm".to_owned(), 0.3)));
+		assert_eq!(parse_key_smoothing("bpm=ema:1"), Ok(("bpm".to_owned(), 1.0)));
+	}
+
+	#[test]
+	fn parse_key_smoothing_rejects_invalid_smoothing() {
+		assert!(parse_key_smoothing("bpm").is_err());
+		assert!(parse_key_smoothing("bpm=sma:3").is_err());
+		assert!(parse_key_smoothing("bpm=ema:0").is_err());
+		assert!(parse_key_smoothing("bpm=ema:1.5").is_err());
+		assert!(parse_key_smoothing("bpm=ema:x").is_err());
+		assert!(parse_key_smoothing("nope=ema:0.3").is_err());
+	}
+
+	#[test]
+	fn find_free_addr_skips_ports_in_use() {
+		let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = taken.local_addr().unwrap();
+		let found = find_free_addr(addr, 10, None).unwrap();
+		assert_ne!(found, addr);
+		assert!(found.port() > addr.port());
+		assert!(find_free_addr(addr, 0, None).is_err());
+	}
+
+	#[test]
+	fn find_free_addr_skips_readonly_addr() {
+		let addr = std::net::TcpListener::bind("127.0.0.1:0")
+			.unwrap()
+			.local_addr()
+			.unwrap();
+		let readonly_addr = SocketAddr::from(([0, 0, 0, 0], addr.port()));
+		let found = find_free_addr(addr, 10, Some(readonly_addr)).unwrap();
+		assert!(found.port() > addr.port());
+	}
+
+	#[test]
+	fn advertise_ip_accepts_ipv4_and_ipv6() {
+		let args = Args::try_parse_from(["heartsock-server", "--advertise-ip", "192.168.1.20"]).unwrap();
+		assert_eq!(args.advertise_ip, Some("192.168.1.20".parse().unwrap()));
+		let args = Args::try_parse_from(["heartsock-server", "--advertise-ip", "fe80::1"]).unwrap();
+		assert_eq!(args.advertise_ip, Some("fe80::1".parse().unwrap()));
+		assert!(Args::try_parse_from(["heartsock-server", "--advertise-ip", "localhost"]).is_err());
+	}
+
+	#[tokio::test]
+	async fn data_dir_must_not_be_a_file() {
+		let dir = websocket::harness::test_dir("data-dir-file");
+		let file = dir.join("data");
+		std::fs::write(&file, "").unwrap();
+		let err = create_data_dir(&file).await.unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			format!("Data directory path ({}) exists and is not a directory", file.display())
+		);
+
+		let nested = dir.join("nested").join("data");
+		create_data_dir(&nested).await.unwrap();
+		assert!(nested.is_dir());
+	}
 }
@@ -1,24 +1,37 @@
-use crate::mdns::{MdnsService, SERVICE};
+use crate::mdns::{detect_local_ip, MdnsService, SERVICE};
 use mdns_sd::{ServiceDaemon, ServiceInfo};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::{
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	time::Duration,
+};
 use thiserror::Error;
 
+/// How often to check that the mDNS daemon is still running
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait for the mDNS daemon to respond to a liveness check
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Error, Debug)]
 pub enum MdnsError {
 	#[error("mDNS service error: {0}")]
 	MdnsDaemon(#[from] mdns_sd::Error),
 	#[error("Detected IP ({0}) is IPv6, which is unsupported for mDNS advertisement")]
 	Ipv6Detected(Ipv6Addr),
+	#[error("Advertise IP ({0}) is IPv6, which is unsupported by the mdns-sd backend")]
+	Ipv6Provided(Ipv6Addr),
 	#[error("Unable to detect local IP: {0}")]
 	DetectionUnknown(#[from] local_ip_address::Error),
 }
 
-pub async fn advertise(port: u16, local_ip: Option<Ipv4Addr>) -> Result<(), MdnsError> {
+pub async fn advertise(listen: SocketAddr, local_ip: Option<IpAddr>) -> Result<(), MdnsError> {
 	// Get the local IP if it wasn't provided
 	let ip = match local_ip {
-		Some(ip) => Ok(ip),
-		None => get_local_ip(),
+		Some(IpAddr::V4(ip4)) => Ok(ip4),
+		Some(IpAddr::V6(ip6)) => Err(MdnsError::Ipv6Provided(ip6)),
+		None => get_local_ip(listen.ip()),
 	}?;
+	let port = listen.port();
 
 	// Create a daemon
 	tracing::info!("Creating mDNS service daemon");
@@ -29,7 +42,7 @@ pub async fn advertise(port: u16, local_ip: Option<Ipv4Addr>) -> Result<(), Mdns
 		service_type,
 		instance_name,
 	} = SERVICE;
-	let hostname = format!("{}.local.", ip);
+	let hostname = hostname(ip.into());
 	let service = ServiceInfo::new(service_type, instance_name, &hostname, ip, port, None)?;
 
 	// Register the service
@@ -39,18 +52,116 @@ pub async fn advertise(port: u16, local_ip: Option<Ipv4Addr>) -> Result<(), Mdns
 		service.get_addresses(),
 		service.get_port()
 	);
-	mdns.register(service).map_err(|err| err.into())
+	mdns.register(service.clone())?;
+
+	// Keep an eye on the daemon, since it can stop on some platforms (e.g. after sleeping and resuming)
+	tokio::spawn(watchdog(mdns, service));
+	Ok(())
+}
+
+/// Periodically checks that the daemon is still responding, recreating it and re-registering the service if not
+async fn watchdog(mut mdns: ServiceDaemon, service: ServiceInfo) {
+	let mut interval = tokio::time::interval(WATCHDOG_INTERVAL);
+	interval.tick().await;
+
+	loop {
+		interval.tick().await;
+		restart_if_dead(&mut mdns, &service).await;
+	}
 }
 
-fn get_local_ip() -> Result<Ipv4Addr, MdnsError> {
-	match local_ip_address::local_ip() {
-		Ok(ip) => {
-			tracing::info!("Detected local IP: {}", ip);
-			match ip {
-				IpAddr::V4(ip4) => Ok(ip4),
-				IpAddr::V6(ip6) => Err(MdnsError::Ipv6Detected(ip6)),
-			}
+/// Recreates the daemon and re-registers the service if the daemon has stopped responding, returning whether it did
+async fn restart_if_dead(mdns: &mut ServiceDaemon, service: &ServiceInfo) -> bool {
+	if is_alive(mdns).await {
+		return false;
+	}
+
+	tracing::warn!("mDNS service daemon stopped responding - restarting it");
+	let _ = mdns.shutdown();
+	let result = ServiceDaemon::new().and_then(|new_mdns| {
+		new_mdns.register(service.clone())?;
+		Ok(new_mdns)
+	});
+	match result {
+		Ok(new_mdns) => {
+			tracing::info!("mDNS service daemon restarted and service re-registered");
+			*mdns = new_mdns;
+			true
+		}
+		Err(err) => {
+			tracing::error!("Unable to restart mDNS service daemon (will retry): {}", err);
+			false
 		}
-		Err(err) => Err(err.into()),
+	}
+}
+
+/// Checks whether the daemon is still running by requesting its metrics
+async fn is_alive(mdns: &ServiceDaemon) -> bool {
+	match mdns.get_metrics() {
+		Ok(metrics) => matches!(
+			tokio::time::timeout(WATCHDOG_TIMEOUT, metrics.recv_async()).await,
+			Ok(Ok(..))
+		),
+		Err(..) => false,
+	}
+}
+
+/// Builds the hostname to advertise for an IP. IPv6 addresses are written out in full with hyphens instead of colons
+/// (which aren't valid in hostnames), so the label never starts or ends with a hyphen from "::" shortening.
+fn hostname(ip: IpAddr) -> String {
+	match ip {
+		IpAddr::V4(ip4) => format!("{}.local.", ip4),
+		IpAddr::V6(ip6) => {
+			let segments: Vec<String> = ip6.segments().iter().map(|segment| format!("{:x}", segment)).collect();
+			format!("{}.local.", segments.join("-"))
+		}
+	}
+}
+
+fn get_local_ip(listen_ip: IpAddr) -> Result<Ipv4Addr, MdnsError> {
+	match detect_local_ip(listen_ip)? {
+		IpAddr::V4(ip4) => Ok(ip4),
+		IpAddr::V6(ip6) => Err(MdnsError::Ipv6Detected(ip6)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn dead_daemon_is_restarted_with_service_registered() {
+		let ip = Ipv4Addr::LOCALHOST;
+		let service = ServiceInfo::new(
+			SERVICE.service_type,
+			SERVICE.instance_name,
+			&hostname(ip.into()),
+			ip,
+			9001,
+			None,
+		)
+		.unwrap();
+		let mut mdns = ServiceDaemon::new().unwrap();
+		mdns.register(service.clone()).unwrap();
+		assert!(!restart_if_dead(&mut mdns, &service).await);
+
+		// Kill the daemon, then make sure the new one has the service registered
+		mdns.shutdown().unwrap();
+		assert!(restart_if_dead(&mut mdns, &service).await);
+		let metrics = mdns.get_metrics().unwrap().recv_async().await.unwrap();
+		assert_eq!(metrics.get("register"), Some(&1));
+		mdns.shutdown().unwrap();
+	}
+
+	#[test]
+	fn hostname_uses_ipv4_address() {
+		assert_eq!(hostname("192.168.1.20".parse().unwrap()), "192.168.1.20.local.");
+	}
+
+	#[test]
+	fn hostname_writes_out_ipv6_address_with_hyphens() {
+		assert_eq!(hostname("fe80::1".parse().unwrap()), "fe80-0-0-0-0-0-0-1.local.");
+		assert_eq!(hostname("::1".parse().unwrap()), "0-0-0-0-0-0-0-1.local.");
+		assert_eq!(hostname("::".parse().unwrap()), "0-0-0-0-0-0-0-0.local.");
 	}
 }
@@ -1,15 +1,43 @@
-#[cfg(all(feature = "simple-mdns", feature = "mdns-sd"))]
-compile_error!("feature \"simple-mdns\" and feature \"mdns-sd\" cannot be enabled at the same time");
-
 #[cfg(feature = "simple-mdns")]
 pub mod simple_mdns;
-#[cfg(feature = "simple-mdns")]
-pub use self::simple_mdns::{advertise, MdnsError};
 
 #[cfg(feature = "mdns-sd")]
 pub mod mdns_sd;
-#[cfg(feature = "mdns-sd")]
-pub use self::mdns_sd::{advertise, MdnsError};
+
+use anyhow::anyhow;
+use std::net::{IpAddr, SocketAddr};
+
+/// Implementation to use for mDNS advertisement (each one is only available if its feature is enabled)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum MdnsBackend {
+	/// simple-mdns (supports IPv6)
+	Simple,
+	/// mdns-sd
+	Sd,
+}
+
+impl Default for MdnsBackend {
+	fn default() -> Self {
+		if cfg!(feature = "mdns-sd") {
+			Self::Sd
+		} else {
+			Self::Simple
+		}
+	}
+}
+
+/// Advertises the server via mDNS with the chosen backend
+pub async fn advertise(backend: MdnsBackend, listen: SocketAddr, local_ip: Option<IpAddr>) -> anyhow::Result<()> {
+	match backend {
+		#[cfg(feature = "simple-mdns")]
+		MdnsBackend::Simple => simple_mdns::advertise(listen, local_ip).await?,
+		#[cfg(feature = "mdns-sd")]
+		MdnsBackend::Sd => mdns_sd::advertise(listen, local_ip).await?,
+		#[allow(unreachable_patterns)]
+		backend => return Err(anyhow!("The {:?} mDNS backend isn't enabled in this build", backend)),
+	}
+	Ok(())
+}
 
 #[derive(Debug)]
 pub struct MdnsService<'a> {
@@ -21,3 +49,159 @@ pub static SERVICE: MdnsService = MdnsService {
 	service_type: "_heartsock._tcp.local.",
 	instance_name: "❤️🧦",
 };
+
+/// Determines the local IP to advertise, which is the listen IP if it's a concrete address (so the advertisement can't
+/// disagree with what's actually bound), or else the detected local IP, falling back to the first non-loopback
+/// interface address when detection fails
+fn detect_local_ip(listen_ip: IpAddr) -> Result<IpAddr, local_ip_address::Error> {
+	choose_local_ip(
+		listen_ip,
+		local_ip_address::local_ip,
+		local_ip_address::list_afinet_netifas,
+	)
+}
+
+/// Chooses the local IP to advertise like [`detect_local_ip`], using the given functions to detect the local IP and
+/// list the network interfaces' addresses
+fn choose_local_ip<D, L>(listen_ip: IpAddr, detect: D, list_interfaces: L) -> Result<IpAddr, local_ip_address::Error>
+where
+	D: FnOnce() -> Result<IpAddr, local_ip_address::Error>,
+	L: FnOnce() -> Result<Vec<(String, IpAddr)>, local_ip_address::Error>,
+{
+	if !listen_ip.is_unspecified() {
+		tracing::info!("Using listen IP: {}", listen_ip);
+		return Ok(listen_ip);
+	}
+
+	let err = match detect() {
+		Ok(ip) => {
+			tracing::info!("Detected local IP: {}", ip);
+			return Ok(ip);
+		}
+		Err(err) => err,
+	};
+
+	// Look for an interface with a non-loopback address, preferring IPv4
+	tracing::warn!(
+		"Unable to detect local IP ({}) - falling back to searching network interfaces",
+		err
+	);
+	match list_interfaces() {
+		Ok(ifas) => {
+			let mut ips: Vec<(String, IpAddr)> = ifas.into_iter().filter(|(_, ip)| !ip.is_loopback()).collect();
+			ips.sort_by_key(|(_, ip)| ip.is_ipv6());
+			if let Some((name, ip)) = ips.into_iter().next() {
+				tracing::info!("Using IP of network interface {}: {}", name, ip);
+				return Ok(ip);
+			}
+			tracing::warn!("No network interfaces with a non-loopback IP found");
+		}
+		Err(err) => tracing::warn!("Unable to list network interfaces: {}", err),
+	}
+
+	Err(err)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use local_ip_address::Error;
+
+	/// Unspecified IPv4 address to listen on, so the local IP has to be detected
+	const UNSPECIFIED: IpAddr = IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED);
+
+	#[test]
+	fn detected_ip_is_used_first() {
+		let ip = choose_local_ip(UNSPECIFIED, || Ok("192.168.1.20".parse().unwrap()), || unreachable!());
+		assert_eq!(ip.unwrap(), "192.168.1.20".parse::<IpAddr>().unwrap());
+	}
+
+	#[test]
+	fn concrete_listen_ip_is_used_without_detection() {
+		for listen_ip in ["192.168.1.50", "fd00::50"] {
+			let listen_ip: IpAddr = listen_ip.parse().unwrap();
+			let ip = choose_local_ip(listen_ip, || unreachable!(), || unreachable!());
+			assert_eq!(ip.unwrap(), listen_ip);
+		}
+	}
+
+	#[test]
+	fn failed_detection_falls_back_to_non_loopback_interface_preferring_ipv4() {
+		let interfaces = vec![
+			("lo".to_owned(), "127.0.0.1".parse().unwrap()),
+			("eth0".to_owned(), "fe80::1".parse().unwrap()),
+			("eth1".to_owned(), "10.0.0.5".parse().unwrap()),
+		];
+		let ip = choose_local_ip(UNSPECIFIED, || Err(Error::LocalIpAddressNotFound), || Ok(interfaces));
+		assert_eq!(ip.unwrap(), "10.0.0.5".parse::<IpAddr>().unwrap());
+	}
+
+	#[test]
+	fn failed_detection_without_usable_interfaces_returns_detection_error() {
+		let loopback = vec![("lo".to_owned(), "127.0.0.1".parse().unwrap())];
+		let ip = choose_local_ip(UNSPECIFIED, || Err(Error::LocalIpAddressNotFound), || Ok(loopback));
+		assert!(matches!(ip, Err(Error::LocalIpAddressNotFound)));
+
+		let ip = choose_local_ip(
+			UNSPECIFIED,
+			|| Err(Error::LocalIpAddressNotFound),
+			|| Err(Error::StrategyError("no interfaces".to_owned())),
+		);
+		assert!(matches!(ip, Err(Error::LocalIpAddressNotFound)));
+	}
+
+	#[test]
+	fn default_backend_is_enabled() {
+		let expected = if cfg!(feature = "mdns-sd") {
+			MdnsBackend::Sd
+		} else {
+			MdnsBackend::Simple
+		};
+		assert_eq!(MdnsBackend::default(), expected);
+	}
+
+	#[tokio::test]
+	async fn each_backend_can_be_selected_at_runtime() {
+		let listen = SocketAddr::from(([127, 0, 0, 1], 9001));
+		for backend in [MdnsBackend::Simple, MdnsBackend::Sd] {
+			let enabled = match backend {
+				MdnsBackend::Simple => cfg!(feature = "simple-mdns"),
+				MdnsBackend::Sd => cfg!(feature = "mdns-sd"),
+			};
+
+			// Enabled backends may still fail if multicast isn't available here, but not because of the selection
+			match advertise(backend, listen, None).await {
+				Ok(()) => assert!(enabled),
+				Err(err) => assert_eq!(
+					err.to_string().contains("isn't enabled in this build"),
+					!enabled,
+					"{}",
+					err
+				),
+			}
+		}
+	}
+
+	#[cfg(feature = "mdns-sd")]
+	#[tokio::test]
+	async fn mdns_sd_backend_rejects_ipv6_advertise_ip() {
+		let listen = SocketAddr::from(([0, 0, 0, 0], 9001));
+		let err = advertise(MdnsBackend::Sd, listen, Some("fe80::1".parse().unwrap()))
+			.await
+			.unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"Advertise IP (fe80::1) is IPv6, which is unsupported by the mdns-sd backend"
+		);
+	}
+
+	#[cfg(not(feature = "simple-mdns"))]
+	#[tokio::test]
+	async fn disabled_backend_is_rejected_before_using_advertise_ip() {
+		let listen = SocketAddr::from(([0, 0, 0, 0], 9001));
+		let err = advertise(MdnsBackend::Simple, listen, Some("fe80::1".parse().unwrap()))
+			.await
+			.unwrap_err();
+		assert_eq!(err.to_string(), "The Simple mDNS backend isn't enabled in this build");
+	}
+}
@@ -1,4 +1,4 @@
-use crate::mdns::{MdnsService, SERVICE};
+use crate::mdns::{detect_local_ip, MdnsService, SERVICE};
 use simple_mdns::async_discovery::ServiceDiscovery;
 use std::net::{IpAddr, SocketAddr};
 use thiserror::Error;
@@ -11,12 +11,13 @@ pub enum MdnsError {
 	DetectionUnknown(#[from] local_ip_address::Error),
 }
 
-pub async fn advertise(port: u16, local_ip: Option<IpAddr>) -> Result<(), MdnsError> {
+pub async fn advertise(listen: SocketAddr, local_ip: Option<IpAddr>) -> Result<(), MdnsError> {
 	// Get the local IP if it wasn't provided
 	let ip = match local_ip {
-		Some(ip) => Ok(ip),
-		None => get_local_ip(),
-	}?;
+		Some(ip) => ip,
+		None => detect_local_ip(listen.ip())?,
+	};
+	let port = listen.port();
 
 	let MdnsService {
 		service_type,
@@ -37,13 +38,3 @@ pub async fn advertise(port: u16, local_ip: Option<IpAddr>) -> Result<(), MdnsEr
 		.await
 		.map_err(|err| err.into())
 }
-
-fn get_local_ip() -> Result<IpAddr, MdnsError> {
-	match local_ip_address::local_ip() {
-		Ok(ip) => {
-			tracing::info!("Detected local IP: {}", ip);
-			Ok(ip)
-		}
-		Err(err) => Err(err.into()),
-	}
-}
@@ -0,0 +1,100 @@
+use futures_util::{SinkExt, StreamExt};
+use std::{
+	f64::consts::TAU,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	time::{Duration, Instant},
+};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+/// How often the simulated tracker sends new values
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many times to try connecting to the server before giving up (it may not be listening yet)
+const CONNECT_ATTEMPTS: u32 = 5;
+
+/// Heart rate that the simulated heart rate wanders around
+const RESTING_BPM: f64 = 75.0;
+
+/// How long it takes for the simulated battery to drain by 1%
+const BATTERY_DRAIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Runs a simulated tracker that connects to the server like a real one, sending a heart rate that wanders around a
+/// resting rate and a slowly draining battery level, authenticating with a token first if there is one
+pub async fn run(listen: SocketAddr, token: Option<String>) -> Result<(), tungstenite::Error> {
+	// Connect to the server over loopback if it's listening on all interfaces
+	let ip = match listen.ip() {
+		IpAddr::V4(ip4) if ip4.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+		IpAddr::V6(ip6) if ip6.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+		ip => ip,
+	};
+	let url = format!("ws://{}", SocketAddr::new(ip, listen.port()));
+
+	let mut attempt = 1;
+	let socket = loop {
+		match tokio_tungstenite::connect_async(&url).await {
+			Ok((socket, _)) => break socket,
+			Err(err) if attempt < CONNECT_ATTEMPTS => {
+				tracing::debug!("Simulated tracker unable to connect to {} ({}) - retrying", url, err);
+				attempt += 1;
+				tokio::time::sleep(INTERVAL).await;
+			}
+			Err(err) => return Err(err),
+		}
+	};
+	tracing::info!("Simulated tracker connected to {}", url);
+
+	// Nothing the server sends matters, but it still needs to be read so pings get answered
+	let (mut sink, mut stream) = socket.split();
+	tokio::spawn(async move { while let Some(Ok(..)) = stream.next().await {} });
+
+	if let Some(token) = token {
+		sink.send(Message::Text(format!("auth {}", token))).await?;
+	}
+	sink.send(Message::Text("quiet on".to_owned())).await?;
+	sink.send(Message::Text("claim".to_owned())).await?;
+
+	let start = Instant::now();
+	let mut interval = tokio::time::interval(INTERVAL);
+	loop {
+		interval.tick().await;
+
+		// Combine a slow swell with a faster ripple and some noise, so the heart rate looks alive
+		let secs = start.elapsed().as_secs_f64();
+		let noise = rand::random::<f64>() * 4.0 - 2.0;
+		let bpm = RESTING_BPM + 12.0 * (secs * TAU / 90.0).sin() + 4.0 * (secs * TAU / 13.0).sin() + noise;
+		let drained = (secs / BATTERY_DRAIN_INTERVAL.as_secs_f64()) as u8;
+		let battery = 100u8.saturating_sub(drained);
+
+		sink.send(Message::Text(format!("set bpm {}", bpm.round() as u8)))
+			.await?;
+		sink.send(Message::Text(format!("set battery {}", battery))).await?;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::websocket::{harness::*, ServerConfig};
+
+	#[tokio::test]
+	async fn simulated_tracker_sends_changing_values() {
+		let server = test_server(ServerConfig::default());
+		let address = serve(&server, ServerConfig::default()).await;
+		let mut observer = TestClient::connect(&server).await;
+		tokio::spawn(run(address, None));
+
+		assert_eq!(observer.recv().await, "tracker: 1");
+		let mut bpms = Vec::new();
+		while bpms.len() < 3 {
+			let line = observer.recv_within(INTERVAL * 10).await;
+			if let Some(bpm) = line.strip_prefix("bpm: ") {
+				bpms.push(bpm.parse::<f64>().unwrap());
+			} else {
+				assert_eq!(line, "battery: 100");
+			}
+		}
+		assert!(bpms
+			.iter()
+			.all(|bpm| (RESTING_BPM - 20.0..=RESTING_BPM + 20.0).contains(bpm)));
+	}
+}
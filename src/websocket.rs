@@ -1,7 +1,32 @@
+use crate::logs::{LevelHandle, LogForwarder};
 use async_trait::async_trait;
-use ezsockets::{Server, Session, Socket};
-use std::{collections::HashMap, fmt::Display, fs, net::SocketAddr, path::PathBuf};
-use tokio::net::ToSocketAddrs;
+use ezsockets::{CloseCode, CloseFrame, Server, Session, Sink, Socket};
+use std::{
+	collections::{HashMap, HashSet},
+	fmt::Display,
+	fs::{self, File, OpenOptions},
+	io::{self, BufWriter, Write},
+	net::{IpAddr, SocketAddr},
+	path::{Component, Path, PathBuf},
+	str::FromStr,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex, PoisonError,
+	},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+	io::{AsyncRead, AsyncWrite},
+	net::{TcpListener, ToSocketAddrs},
+	sync::oneshot,
+	time::Instant,
+};
+use tokio_tungstenite::tungstenite::{
+	self,
+	handshake::server::{ErrorResponse, Request, Response},
+	http::StatusCode,
+};
+use tracing::metadata::LevelFilter;
 
 /// Type to use for Session IDs
 pub type SessionID = u32;
@@ -9,6 +34,27 @@ pub type SessionID = u32;
 /// Type to use for values
 pub type Value = u8;
 
+/// Type to use for tracker priorities, where higher priorities take over the tracker role from lower ones
+pub type Priority = u32;
+
+/// How often to check the watched file for changes
+const WATCH_FILE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often buffered audit log records are flushed to the file
+const AUDIT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Response to commands from an origin that has gone over its rate limit
+const RATE_LIMITED: &str = "error: rate limited";
+
+/// Length of the windows that commands from each origin are counted in
+const ORIGIN_WINDOW: Duration = Duration::from_secs(1);
+
+/// How often the command windows of origins that have gone quiet are cleared out
+const ORIGIN_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Max number of characters in an operator message
+const MAX_MESSAGE_LEN: usize = 200;
+
 /// Key used for storing/retrieving the tracker value
 pub const KEY_TRACKER: &str = "tracker";
 /// Key used for storing/retrieving the BPM value
@@ -16,27 +62,533 @@ pub const KEY_BPM: &str = "bpm";
 /// Key used for storing/retrieving the battery value
 pub const KEY_BATTERY: &str = "battery";
 
+/// Description of a value that trackers can set
+#[derive(Debug)]
+pub struct ValueSpec {
+	/// Key used for storing/retrieving the value
+	pub key: &'static str,
+	/// Unit the value is measured in
+	pub unit: &'static str,
+	/// Human-readable name of the value
+	pub label: Option<&'static str>,
+}
+
+/// Values that trackers can set
+pub static VALUE_SPECS: [ValueSpec; 2] = [
+	ValueSpec {
+		key: KEY_BPM,
+		unit: "BPM",
+		label: Some("Heart rate"),
+	},
+	ValueSpec {
+		key: KEY_BATTERY,
+		unit: "%",
+		label: Some("Battery"),
+	},
+];
+
+/// Retrieves the spec for a value that trackers can set
+pub fn value_spec(key: &str) -> Option<&'static ValueSpec> {
+	VALUE_SPECS.iter().find(|spec| spec.key == key)
+}
+
+/// Configuration for a Heartsock server
+#[derive(Debug, Default)]
+pub struct ServerConfig {
+	/// Directory to write value files to
+	pub data_dir: Option<PathBuf>,
+	/// Which files to write to the data directory
+	pub data_format: DataFormat,
+	/// Token that sessions can authenticate with to gain access to admin commands
+	pub admin_token: Option<String>,
+	/// Keys that each access control token permits setting (if there are any, sessions can only set keys their tokens
+	/// permit, other than admins)
+	pub acl: HashMap<String, HashSet<String>>,
+	/// Whether sessions echo the command each response is for by default
+	pub echo_commands: bool,
+	/// Whether sessions must claim the tracker role before setting values, rather than being promoted automatically
+	pub explicit_tracker: bool,
+	/// Minimum time between broadcasts of changes to each key (keys without one are broadcast immediately)
+	pub broadcast_intervals: HashMap<String, Duration>,
+	/// Whether values that have never been set are reported as "unset" instead of 0
+	pub distinguish_unset: bool,
+	/// How often to rewrite all value files, even if their values haven't changed
+	pub data_flush_interval: Option<Duration>,
+	/// How often to write changed values to the data directory (they're written as soon as they change if not set)
+	pub data_write_interval: Option<Duration>,
+	/// Names of headers to capture from clients' upgrade requests (lowercase)
+	pub capture_headers: Vec<String>,
+	/// Text reported in place of settable values while there's no tracker
+	pub no_tracker_placeholder: Option<String>,
+	/// Key that set/get commands without one are for
+	pub default_key: Option<String>,
+	/// Whether to seed the values from the files in the data directory on startup
+	pub restore_from_data_dir: bool,
+	/// Whether to write a JSON Lines record of each value change to stdout
+	pub stdout_jsonl: bool,
+	/// Max number of sessions that clients from a single IP can have at once
+	pub max_sessions_per_ip: Option<usize>,
+	/// Max number of sessions there can be at once
+	pub max_sessions: Option<usize>,
+	/// Max number of commands per second from all sessions with the same origin
+	pub origin_rate_limit: Option<u32>,
+	/// Whether to send each session its ID when it connects
+	pub send_id_on_connect: bool,
+	/// Whether command arguments (such as value keys) are matched case-sensitively, rather than just command names
+	pub case_sensitive_args: bool,
+	/// Whether to skip sending each session the current values when it connects
+	pub skip_snapshot_on_connect: bool,
+	/// Whether sessions are shown random opaque IDs instead of their sequential ones
+	pub opaque_session_ids: bool,
+	/// Handle for changing the max log level at runtime
+	pub log_level: Option<LevelHandle>,
+	/// How often to send all sessions a keepalive line
+	pub keepalive_interval: Option<Duration>,
+	/// How long a value trackers set can go without an update before it's considered stale
+	pub stale_after: Option<Duration>,
+	/// Largest change to each key's value that a single set can make
+	pub max_deltas: HashMap<String, Value>,
+	/// Smoothing factor of the exponential moving average to report instead of each smoothed key's raw value
+	pub smoothing: HashMap<String, f64>,
+	/// Name of the backend advertising the server via mDNS (if it's being advertised)
+	pub mdns_backend: Option<String>,
+	/// Whether to include each key's sequence number in the lines notifying sessions of its value
+	pub sequence_numbers: bool,
+	/// Whether the tracker role is pinned to the IP of the first tracker, so sessions from other IPs can never set values
+	pub pin_tracker_ip: bool,
+	/// Whether changes to several keys that are broadcast at the same time are combined into a single line
+	pub combine_broadcasts: bool,
+	/// Whether to include the ID of the session that set each value in the lines notifying sessions of it
+	pub broadcast_source: bool,
+	/// File to append a record of every set command to
+	pub audit_log: Option<PathBuf>,
+	/// File of key=value lines to watch, applying its values whenever it changes
+	pub watch_file: Option<PathBuf>,
+	/// Whether to accept the old protocol's bare numbers and respond to "get" with just the value
+	pub legacy_protocol: bool,
+	/// Whether to tell the tracker when there stop being and start being other sessions
+	pub observer_events: bool,
+	/// Whether sets with an older client timestamp than the latest one accepted for the key are rejected
+	pub monotonic_timestamps: bool,
+	/// Longest to wait for the server to finish up when shutting down
+	pub shutdown_timeout: Duration,
+	/// Whether commands with anything other than printable ASCII characters and spaces are rejected
+	pub ascii_commands: bool,
+	/// Whether older sessions are closed when a newer session gives the same device ID
+	pub dedupe_devices: bool,
+}
+
+impl ServerConfig {
+	/// Summarizes the configuration for troubleshooting, leaving out secrets (tokens are only reported as counts)
+	pub fn summary(&self) -> String {
+		fn optional<T: Display>(val: &Option<T>) -> String {
+			val.as_ref().map_or_else(|| "none".to_owned(), T::to_string)
+		}
+
+		let keys = VALUE_SPECS.iter().map(|spec| format!("{}(0-{})", spec.key, Value::MAX));
+		let entries = [
+			("keys", format_list(keys)),
+			("admin-auth", yes_no(self.admin_token.is_some()).to_owned()),
+			("acl-tokens", self.acl.len().to_string()),
+			("mdns", optional(&self.mdns_backend)),
+			("data-dir", optional(&self.data_dir.as_ref().map(|dir| dir.display()))),
+			("data-format", format!("{:?}", self.data_format).to_lowercase()),
+			("explicit-tracker", yes_no(self.explicit_tracker).to_owned()),
+			("default-key", optional(&self.default_key)),
+			(
+				"broadcast-intervals",
+				format_list(
+					self.broadcast_intervals
+						.iter()
+						.map(|(key, interval)| format!("{}={:?}", key, interval)),
+				),
+			),
+			(
+				"max-deltas",
+				format_list(self.max_deltas.iter().map(|(key, delta)| format!("{}={}", key, delta))),
+			),
+			(
+				"smoothing",
+				format_list(
+					self.smoothing
+						.iter()
+						.map(|(key, alpha)| format!("{}=ema:{}", key, alpha)),
+				),
+			),
+			("max-sessions-per-ip", optional(&self.max_sessions_per_ip)),
+			("max-sessions", optional(&self.max_sessions)),
+			("origin-rate-limit", optional(&self.origin_rate_limit)),
+			(
+				"keepalive",
+				optional(&self.keepalive_interval.map(|interval| format!("{:?}", interval))),
+			),
+			(
+				"stale-after",
+				optional(&self.stale_after.map(|duration| format!("{:?}", duration))),
+			),
+			("legacy-protocol", yes_no(self.legacy_protocol).to_owned()),
+			("observer-events", yes_no(self.observer_events).to_owned()),
+			("monotonic-timestamps", yes_no(self.monotonic_timestamps).to_owned()),
+			("dedupe-devices", yes_no(self.dedupe_devices).to_owned()),
+		];
+		let entries: Vec<String> = entries.iter().map(|(name, val)| format!("{}={}", name, val)).collect();
+		entries.join(" ")
+	}
+}
+
+/// Files to write to the data directory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DataFormat {
+	/// A plain text file for each value (e.g. bpm.txt)
+	#[default]
+	Text,
+	/// A single state.json file with all values, when they were last updated, and whether there's a tracker
+	Json,
+	/// Both the plain text files and state.json
+	Both,
+}
+
+/// Arguments for creating a session, determined by the listener the client connected to and its upgrade request
+#[derive(Debug, Default)]
+pub struct SessionArgs {
+	/// Whether the session is only allowed to read values
+	pub readonly: bool,
+	/// Captured headers from the client's upgrade request, as name/value pairs
+	pub headers: Vec<(String, String)>,
+	/// Origin header from the client's upgrade request (only sent by browsers)
+	pub origin: Option<String>,
+}
+
+/// Optional arguments for setting a value
+#[derive(Debug, Default)]
+pub struct SetOptions {
+	/// Priority of the session for the tracker role
+	pub priority: Option<Priority>,
+	/// How long until the value reverts to what it was before
+	pub ttl: Option<Duration>,
+	/// When the client took the reading, in milliseconds since the Unix epoch
+	pub timestamp: Option<u64>,
+}
+
+/// Numbers of errors a session has run into, by type
+#[derive(Clone, Copy, Debug, Default)]
+struct ErrorCounts {
+	/// Commands that couldn't be understood (unknown commands, malformed arguments, or binary data)
+	parse: u64,
+	/// Sets that were understood but rejected
+	rejected_sets: u64,
+	/// Commands rejected because the session's origin went over its rate limit
+	rate_limited: u64,
+	/// Any other errors (e.g. unauthorized commands)
+	other: u64,
+}
+
+/// Value that a key will revert to once a temporary value expires
+#[derive(Clone, Copy, Debug)]
+struct PendingRevert {
+	/// ID of the revert, to tell whether it has been superseded
+	id: u64,
+	/// Value to revert to
+	val: Value,
+}
+
+/// Commands received from an origin within the current one-second window
+#[derive(Clone, Copy, Debug)]
+struct OriginWindow {
+	/// When the window started
+	started: Instant,
+	/// Number of commands received in the window
+	count: u32,
+}
+
+/// Command windows of each origin, shared by all sessions
+type OriginWindows = Arc<Mutex<HashMap<String, OriginWindow>>>;
+
+/// Limit on how often a session is notified of changes to a key
+#[derive(Clone, Copy, Debug)]
+struct RateLimit {
+	/// Minimum time between notifications
+	interval: Duration,
+	/// When the session was last notified
+	last_sent: Option<Instant>,
+	/// Whether a notification of the latest value is scheduled
+	pending: bool,
+}
+
+/// Sender for the server's response to a message
+pub type Responder = oneshot::Sender<String>;
+
 /// Message data to send from a server
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub enum Message {
-	Ping { id: SessionID },
-	GetVal { id: SessionID, key: String },
-	SetVal { id: SessionID, key: String, val: Value },
+	Ping {
+		respond_to: Responder,
+	},
+	GetVal {
+		key: String,
+		respond_to: Responder,
+	},
+	SetVal {
+		id: SessionID,
+		key: String,
+		val: Value,
+		options: SetOptions,
+		respond_to: Responder,
+	},
+	Claim {
+		id: SessionID,
+		priority: Option<Priority>,
+		respond_to: Responder,
+	},
+	Logout {
+		id: SessionID,
+		respond_to: Responder,
+	},
+	ClaimBackup {
+		id: SessionID,
+		respond_to: Responder,
+	},
+	GrantTracker {
+		public_id: String,
+		duration: Duration,
+		respond_to: Responder,
+	},
+	EndGrant {
+		id: SessionID,
+		grant_id: u64,
+	},
+	FlushBroadcast {
+		keys: Vec<String>,
+	},
+	FlushData,
+	WriteData,
+	Shutdown {
+		respond_to: Responder,
+	},
+	Audit {
+		id: SessionID,
+		command: String,
+		response: String,
+	},
+	FlushAuditLog,
+	PruneOriginWindows,
+	InjectVals {
+		vals: Vec<(String, Value)>,
+	},
+	Keepalive,
+	Revert {
+		key: String,
+		id: u64,
+	},
+	Cas {
+		id: SessionID,
+		key: String,
+		expected: Value,
+		val: Value,
+		respond_to: Responder,
+	},
+	GetPeaks {
+		key: String,
+		respond_to: Responder,
+	},
+	GetUpdated {
+		key: String,
+		respond_to: Responder,
+	},
+	GetStale {
+		respond_to: Responder,
+	},
+	HasTracker {
+		key: String,
+		respond_to: Responder,
+	},
+	SetDeviceId {
+		id: SessionID,
+		device_id: String,
+		replace: bool,
+		respond_to: Responder,
+	},
+	GetChangeCount {
+		key: String,
+		baseline: u64,
+		respond_to: Responder,
+	},
+	GetCapacity {
+		respond_to: Responder,
+	},
+	ResetStats {
+		respond_to: Responder,
+	},
+	Rejected {
+		reason: String,
+	},
+	GetRejections {
+		respond_to: Responder,
+	},
+	ResetRejections {
+		respond_to: Responder,
+	},
+	Broadcast {
+		text: String,
+		respond_to: Responder,
+	},
+	TrackerUptime {
+		respond_to: Responder,
+	},
+	Sync {
+		respond_to: Responder,
+	},
+	GetState {
+		respond_to: Responder,
+	},
+	Snapshot {
+		path: PathBuf,
+		respond_to: Responder,
+	},
+	SetKeyEnabled {
+		key: String,
+		enabled: bool,
+		respond_to: Responder,
+	},
+	Pin {
+		key: String,
+		val: Option<Value>,
+		respond_to: Responder,
+	},
+	Drain {
+		timeout: Option<Duration>,
+		respond_to: Responder,
+	},
+	Redirect {
+		url: String,
+		respond_to: Responder,
+	},
+	CloseAll,
+	Events {
+		id: SessionID,
+		enabled: bool,
+		respond_to: Responder,
+	},
+	Mute {
+		id: SessionID,
+		key: String,
+		muted: bool,
+		respond_to: Responder,
+	},
+	GetSubscriptions {
+		id: SessionID,
+		respond_to: Responder,
+	},
+	GetSessionState {
+		id: SessionID,
+		respond_to: Responder,
+	},
+	Rate {
+		id: SessionID,
+		key: String,
+		interval: Option<Duration>,
+		respond_to: Responder,
+	},
+	FlushRate {
+		id: SessionID,
+		key: String,
+	},
+}
+
+/// Lowest and highest values observed for a key
+#[derive(Clone, Copy, Debug)]
+pub struct Peaks {
+	/// Lowest value observed
+	pub min: Value,
+	/// Highest value observed
+	pub max: Value,
 }
 
 pub struct HeartsockServer {
 	/// Currently connected sessions
 	sessions: HashMap<SessionID, Session<SessionID, Message>>,
+	/// IDs shown to clients for currently connected sessions (which differ from the sequential IDs if they're opaque)
+	public_ids: HashMap<SessionID, String>,
+	/// Addresses of the clients of currently connected sessions
+	addresses: HashMap<SessionID, SocketAddr>,
+	/// Sinks of currently connected sessions, for closing them
+	sinks: HashMap<SessionID, Sink>,
+	/// Whether new connections are being refused so the server can be shut down once existing sessions leave
+	draining: Arc<AtomicBool>,
+	/// Responder for a shutdown that's waiting on the remaining sessions to disconnect
+	shutdown: Option<Responder>,
 	/// Handle to use for communication across the server
 	handle: Server<Self>,
 	/// Latest session ID that has been used
 	latest_id: SessionID,
-	/// ID of the session that is the tracker
-	tracker_id: SessionID,
+	/// ID of the session that is the tracker (if there is one)
+	tracker_id: Option<SessionID>,
+	/// When the current tracker was promoted
+	tracker_since: Option<Instant>,
+	/// IP that the tracker role is pinned to (the first tracker's, if pinning is enabled)
+	tracker_ip: Option<IpAddr>,
+	/// ID of the grant the current tracker has the role from (if it was granted by an admin)
+	tracker_grant: Option<u64>,
+	/// Latest grant ID that has been used
+	latest_grant_id: u64,
+	/// Sessions that take over the tracker role if it's lost, in the order they registered
+	backups: Vec<SessionID>,
 	/// Current tracked values
 	values: HashMap<String, Value>,
-	/// Directory to write value files to
-	data_dir: Option<PathBuf>,
+	/// Keys that have changed since their last broadcast, waiting for their broadcast interval
+	pending_broadcasts: HashSet<String>,
+	/// Keys that have been set at least once
+	set_keys: HashSet<String>,
+	/// Exponential moving averages of the values of keys that are smoothed, which are reported instead of the raw values
+	smoothed: HashMap<String, f64>,
+	/// Number of rejected sets by reason since startup or the last reset
+	rejections: HashMap<String, u64>,
+	/// Lowest and highest values set for each key since startup or the last stats reset
+	peaks: HashMap<String, Peaks>,
+	/// Tracker priorities given by sessions (sessions that haven't given one have the lowest priority)
+	priorities: HashMap<SessionID, Priority>,
+	/// Sessions that are subscribed to connect/disconnect events
+	event_subscribers: HashSet<SessionID>,
+	/// Keys each session has muted broadcasts of
+	muted_keys: HashMap<SessionID, HashSet<String>>,
+	/// Limits each session has requested on how often they're notified of changes to keys
+	rate_limits: HashMap<SessionID, HashMap<String, RateLimit>>,
+	/// When each key's value last changed
+	updated_at: HashMap<String, SystemTime>,
+	/// When each key's value was last set (even if it didn't change), for checking whether it's stale
+	set_at: HashMap<String, Instant>,
+	/// Version of the values, incremented whenever one changes
+	state_version: u64,
+	/// Whether there were any sessions other than the tracker when observers were last checked
+	has_observers: bool,
+	/// Latest client timestamp of a set that was accepted for each key (only comparable between sets from the same tracker)
+	timestamps: HashMap<String, u64>,
+	/// Keys that have changed since their values were last written to the data directory (when writes are batched)
+	unwritten_keys: HashSet<String>,
+	/// Client-facing ID of the session that last set each key's value (if it was set by one)
+	sources: HashMap<String, String>,
+	/// Sequence number of each key, incremented whenever its value changes
+	sequences: HashMap<String, u64>,
+	/// Keys that have been disabled by an admin, which can't be set and aren't broadcast
+	disabled_keys: HashSet<String>,
+	/// Keys that have been pinned to a value by an admin, which sets from the tracker don't change
+	pinned_keys: HashSet<String>,
+	/// Values to revert keys to once their temporary values expire
+	pending_reverts: HashMap<String, PendingRevert>,
+	/// Latest revert ID that has been used
+	latest_revert_id: u64,
+	/// Buffered writer for the audit log of set commands (if enabled)
+	audit_log: Option<BufWriter<File>>,
+	/// Where to write JSON Lines records of value changes (stdout, if enabled)
+	jsonl_out: Option<Box<dyn Write + Send + Sync>>,
+	/// Device IDs that sessions have given
+	device_ids: HashMap<SessionID, String>,
+	/// Command windows of each origin, for limiting how many commands they can send
+	origin_windows: OriginWindows,
+	/// Configuration shared with all sessions
+	config: Arc<ServerConfig>,
+	/// Forwarder for sessions that are tailing the logs
+	logs: LogForwarder,
 }
 
 #[async_trait]
@@ -49,11 +601,68 @@ impl ezsockets::ServerExt for HeartsockServer {
 		&mut self,
 		socket: Socket,
 		address: SocketAddr,
-		_args: <Self::Session as ezsockets::SessionExt>::Args,
+		args: <Self::Session as ezsockets::SessionExt>::Args,
 	) -> Result<Session<SessionID, Self::Call>, ezsockets::Error> {
-		// Get a new ID for the session
+		// Get a new ID for the session, along with the one to show clients
 		self.latest_id += 1;
 		let id = self.latest_id;
+		let public_id = if self.config.opaque_session_ids {
+			format!("{:016x}", rand::random::<u64>())
+		} else {
+			id.to_string()
+		};
+
+		// Refuse the connection if the server is full or there are already too many sessions from its IP (a session
+		// still needs to be created for it, but it's closed right away and never added to the map)
+		let ip_sessions = self
+			.addresses
+			.values()
+			.filter(|other| other.ip() == address.ip())
+			.count();
+		let refusal = if self.config.max_sessions.is_some_and(|max| self.sessions.len() >= max) {
+			tracing::warn!(
+				"Refusing connection from {} (already at {} sessions)",
+				address,
+				self.sessions.len()
+			);
+			Some(("error: server is full", "server full"))
+		} else if self.config.max_sessions_per_ip.is_some_and(|max| ip_sessions >= max) {
+			tracing::warn!(
+				"Refusing connection from {} (already has {} sessions from its IP)",
+				address,
+				ip_sessions
+			);
+			Some(("error: too many connections from your address", "too many connections"))
+		} else {
+			None
+		};
+		let refused = refusal.is_some();
+		if let Some((line, reason)) = refusal {
+			let sink = socket.sink.clone();
+			sink.send(ezsockets::Message::Text(line.to_owned())).await;
+			sink.send(ezsockets::Message::Close(Some(CloseFrame {
+				code: CloseCode::Policy,
+				reason: reason.to_owned(),
+			})))
+			.await;
+		}
+
+		// Send the client its ID and the current values (if enabled) straight to the socket before the session is
+		// created, since the session starts handling commands as soon as it is - responses to commands sent right after
+		// connecting would otherwise be able to arrive first. Commands that go through the server can't be handled
+		// before the session is in the map, since calls to the server wait until this returns.
+		let sink = socket.sink.clone();
+		if !refused {
+			if self.config.send_id_on_connect {
+				sink.send(ezsockets::Message::Text(format!("id: {}", public_id))).await;
+			}
+			if !self.config.skip_snapshot_on_connect {
+				for key in self.values.keys() {
+					let line = self.format_line(key);
+					sink.send(ezsockets::Message::Text(line)).await;
+				}
+			}
+		}
 
 		// Create the session and add it to the map
 		let session = Session::create(
@@ -61,17 +670,53 @@ impl ezsockets::ServerExt for HeartsockServer {
 				id,
 				handle,
 				server: self.handle.clone(),
+				config: self.config.clone(),
+				logs: self.logs.clone(),
+				admin: false,
+				permitted_keys: HashSet::new(),
+				echo: self.config.echo_commands,
+				quiet: false,
+				errors: ErrorCounts::default(),
+				readonly: args.readonly,
+				headers: args.headers.clone(),
+				origin: args.origin.clone(),
+				origin_windows: self.origin_windows.clone(),
+				address,
+				public_id: public_id.clone(),
+				connect_sequences: self.sequences.clone(),
+				first_message: true,
 			},
 			id,
 			socket,
 		);
+		if refused {
+			return Ok(session);
+		}
 		self.sessions.insert(id, session.clone());
-		tracing::info!("Session {} created for client connecting from {}", &id, &address);
-
-		// Send the current values
-		for (key, val) in &self.values {
-			session.text(format!("{}: {}", key, val));
+		self.addresses.insert(id, address);
+		self.sinks.insert(id, sink);
+		self.public_ids.insert(id, public_id.clone());
+		let headers = format_headers(&args.headers);
+		if args.readonly {
+			tracing::info!(
+				"Read-only session {} created for client connecting from {}{}",
+				&id,
+				&address,
+				headers
+			);
+		} else {
+			tracing::info!(
+				"Session {} created for client connecting from {}{}",
+				&id,
+				&address,
+				headers
+			);
 		}
+		if self.config.opaque_session_ids {
+			tracing::info!("Session {} has opaque ID {}", id, public_id);
+		}
+		self.send_event(format!("connected {} {}", public_id, address));
+		self.check_observers(false);
 
 		Ok(session)
 	}
@@ -81,49 +726,589 @@ impl ezsockets::ServerExt for HeartsockServer {
 		&mut self,
 		id: <Self::Session as ezsockets::SessionExt>::ID,
 	) -> Result<(), ezsockets::Error> {
-		// Remove the session from the map
-		assert!(
-			self.sessions.remove(&id).is_some(),
-			"Disconnecting session not found in session map"
-		);
-		tracing::info!("Session {} removed for client disconnect", &id);
+		// Remove the session from the map (it may have already been removed if it was found closed while notifying)
+		if self.remove_session(id) {
+			tracing::info!("Session {} removed for client disconnect", &id);
+		} else {
+			tracing::debug!("Session {} already removed before client disconnect", &id);
+		}
 
 		// Reset the tracker ID if it's for the disconnected session
-		if id == self.tracker_id {
+		if self.release_tracker(id) {
 			tracing::info!("Tracker lost (disconnected session {} was the tracker)", &id);
-			self.tracker_id = 0;
-			self.set_val(KEY_TRACKER.to_owned(), 0);
+		}
+		self.check_observers(false);
+
+		// Finish shutting down once the last session is gone
+		if self.sessions.is_empty() {
+			if let Some(respond_to) = self.shutdown.take() {
+				respond(respond_to, "ok".to_owned());
+			}
 		}
 
 		Ok(())
 	}
 
-	// Sends messages to connected sessions
+	// Handles messages from sessions (responding to them) and from the server's own tasks
 	async fn on_call(&mut self, call: Self::Call) -> Result<(), ezsockets::Error> {
 		match call {
 			// ping -> pong
-			Message::Ping { id } => self.get_session(&id)?.text("pong".to_owned()),
+			Message::Ping { respond_to } => respond(respond_to, "pong".to_owned()),
+
+			Message::GetVal { key, respond_to } => {
+				let response = if self.values.contains_key(&key) {
+					format!("{}: {}", key, self.format_val(&key))
+				} else {
+					"error: unknown value key".to_owned()
+				};
+				respond(respond_to, response)
+			}
+
+			Message::SetVal {
+				id,
+				key,
+				val,
+				options,
+				respond_to,
+			} => respond(respond_to, self.set_tracked_val(id, key, val, options)),
+
+			Message::Claim {
+				id,
+				priority,
+				respond_to,
+			} => respond(respond_to, self.claim_tracker(id, priority)),
+
+			Message::ClaimBackup { id, respond_to } => respond(respond_to, self.claim_backup(id)),
+
+			Message::GrantTracker {
+				public_id,
+				duration,
+				respond_to,
+			} => {
+				let id = self
+					.public_ids
+					.iter()
+					.find(|(_, other_public_id)| **other_public_id == public_id)
+					.map(|(id, _)| *id);
+				let response = match id {
+					Some(id) => {
+						tracing::info!("Granting tracker role to session {} for {:?}", id, duration);
+						if self.tracker_id != Some(id) {
+							self.promote_tracker(id);
+						}
+
+						// Take the role away again once the grant is up
+						self.latest_grant_id += 1;
+						let grant_id = self.latest_grant_id;
+						self.tracker_grant = Some(grant_id);
+						let server = self.handle.clone();
+						tokio::spawn(async move {
+							tokio::time::sleep(duration).await;
+							server.call(Message::EndGrant { id, grant_id });
+						});
+						"ok".to_owned()
+					}
+					None => "error: unknown session".to_owned(),
+				};
+				respond(respond_to, response)
+			}
+
+			Message::EndGrant { id, grant_id } => {
+				// Only end the grant if the session still has the role from it
+				if self.tracker_id == Some(id) && self.tracker_grant == Some(grant_id) {
+					tracing::info!("Tracker grant for session {} expired", id);
+					self.release_tracker(id);
+				}
+			}
+
+			// Drop everything the session had from being authenticated: the tracker and backup roles (which it may no
+			// longer be permitted to claim) and the admin-only events
+			Message::Logout { id, respond_to } => {
+				self.backups.retain(|backup_id| *backup_id != id);
+				self.event_subscribers.remove(&id);
+				if self.release_tracker(id) {
+					tracing::info!("Tracker released by session {} logging out", id);
+				}
+				respond(respond_to, "ok".to_owned())
+			}
+
+			Message::Cas {
+				id,
+				key,
+				expected,
+				val,
+				respond_to,
+			} => {
+				// Calls are handled one at a time, so the value can't change between the comparison and the set. The
+				// current value is reported raw (not smoothed or replaced by a placeholder), since that's what the
+				// comparison is against.
+				let current = *self.get_val(&key);
+				let response = if current == expected {
+					self.set_tracked_val(id, key, val, SetOptions::default())
+				} else {
+					format!("error: cas mismatch (current={})", current)
+				};
+				respond(respond_to, response)
+			}
+
+			Message::FlushBroadcast { keys } => self.flush_broadcasts(&keys),
+
+			Message::GetPeaks { key, respond_to } => {
+				let response = match self.peaks.get(&key) {
+					Some(Peaks { min, max }) => format!("min: {} max: {}", min, max),
+					None => format!("error: no {} values recorded", key),
+				};
+				respond(respond_to, response)
+			}
+
+			Message::GetUpdated { key, respond_to } => {
+				let response = match self.updated_at.get(&key) {
+					_ if !self.values.contains_key(&key) => "error: unknown value key".to_owned(),
+					Some(updated_at) => format!("updated {}: {}", key, unix_millis(*updated_at)),
+					None => format!("updated {}: never", key),
+				};
+				respond(respond_to, response)
+			}
+
+			Message::GetStale { respond_to } => {
+				let keys = self.values.keys().filter(|key| self.is_stale(key));
+				respond(respond_to, format!("stale: {}", format_list(keys)))
+			}
+
+			Message::GetCapacity { respond_to } => {
+				let sessions = self.sessions.len();
+				let response = match self.config.max_sessions {
+					Some(max) => format!(
+						"capacity: sessions={} max={} utilization={}%",
+						sessions,
+						max,
+						(sessions * 100).checked_div(max).unwrap_or(100)
+					),
+					None => format!("capacity: sessions={} max=none utilization=0%", sessions),
+				};
+				respond(respond_to, response)
+			}
+
+			Message::SetDeviceId {
+				id,
+				device_id,
+				replace,
+				respond_to,
+			} => {
+				let duplicates: Vec<SessionID> = self
+					.device_ids
+					.iter()
+					.filter(|(other_id, other_device_id)| **other_id != id && **other_device_id == device_id)
+					.map(|(other_id, _)| *other_id)
+					.collect();
+				for other_id in duplicates {
+					// Only sessions that could claim the tracker role themselves get to close another session, so a
+					// client can't knock the tracker off just by knowing its device ID
+					if !self.config.dedupe_devices || !replace {
+						tracing::warn!(
+							"Session {} is from the same device ({}) as session {}",
+							id,
+							device_id,
+							other_id
+						);
+						continue;
+					}
+
+					tracing::warn!(
+						"Session {} is from the same device ({}) as session {} - closing the older one",
+						id,
+						device_id,
+						other_id
+					);
+					// Sending to a sink whose connection has already ended would panic
+					if let Some(sink) = self.sinks.get(&other_id).filter(|sink| !sink.is_closed()) {
+						let frame = CloseFrame {
+							code: CloseCode::Policy,
+							reason: "replaced by a newer connection".to_owned(),
+						};
+						sink.send(ezsockets::Message::Close(Some(frame))).await;
+					}
+
+					// Treat the older session as disconnected right away, so the tracker role goes through the usual
+					// failover instead of staying with a connection that's going away (the newer session still has to
+					// claim it the usual way)
+					self.remove_session(other_id);
+					if self.release_tracker(other_id) {
+						tracing::info!("Tracker lost (session {} was replaced by session {})", other_id, id);
+					}
+				}
+				self.device_ids.insert(id, device_id);
+				respond(respond_to, "ok".to_owned())
+			}
+
+			// Each change bumps the key's sequence number, so the count since some point is the difference from then
+			Message::GetChangeCount {
+				key,
+				baseline,
+				respond_to,
+			} => {
+				let response = if self.values.contains_key(&key) {
+					let seq = self.sequences.get(&key).copied().unwrap_or_default();
+					format!("change-count {}: {}", key, seq.saturating_sub(baseline))
+				} else {
+					"error: unknown value key".to_owned()
+				};
+				respond(respond_to, response)
+			}
+
+			// There's a single tracker for all keys, so every key trackers can set has one whenever it's claimed
+			Message::HasTracker { key, respond_to } => {
+				let response = match value_spec(&key) {
+					Some(_) if self.tracker_id.is_some() => format!("has-tracker {}: yes", key),
+					Some(_) => format!("has-tracker {}: no", key),
+					None => "error: unknown value key".to_owned(),
+				};
+				respond(respond_to, response)
+			}
+
+			Message::Rejected { reason } => *self.rejections.entry(reason).or_default() += 1,
+
+			Message::GetRejections { respond_to } => {
+				let total: u64 = self.rejections.values().sum();
+				let mut counts: Vec<String> = self
+					.rejections
+					.iter()
+					.map(|(reason, count)| format!("{}={}", reason, count))
+					.collect();
+				counts.sort();
+				counts.insert(0, format!("total={}", total));
+				respond(respond_to, format!("rejections: {}", counts.join(" ")))
+			}
+
+			Message::ResetRejections { respond_to } => {
+				tracing::info!("Resetting rejection counts");
+				self.rejections.clear();
+				respond(respond_to, "ok".to_owned())
+			}
+
+			Message::ResetStats { respond_to } => {
+				tracing::info!("Resetting stats");
+				self.peaks.clear();
+				respond(respond_to, "ok".to_owned())
+			}
+
+			Message::Drain { timeout, respond_to } => {
+				let response = if self.draining.swap(true, Ordering::Relaxed) {
+					"error: already draining".to_owned()
+				} else {
+					tracing::info!("Draining - refusing new connections");
+					self.send_line("draining".to_owned(), |_, _| true);
+
+					// Close the remaining sessions once the timeout is up
+					if let Some(timeout) = timeout {
+						let server = self.handle.clone();
+						tokio::spawn(async move {
+							tokio::time::sleep(timeout).await;
+							server.call(Message::CloseAll);
+						});
+					}
+
+					"ok".to_owned()
+				};
+				respond(respond_to, response)
+			}
+
+			Message::Redirect { url, respond_to } => {
+				tracing::info!("Redirecting all sessions to {}", url);
+				self.send_line(format!("redirect: {}", url), |_, _| true);
+				respond(respond_to, "ok".to_owned())
+			}
+
+			Message::CloseAll => self.close_all().await,
+
+			Message::Sync { respond_to } => {
+				let mut keys: Vec<&String> = self.values.keys().collect();
+				keys.sort();
+				let vals: Vec<String> = keys
+					.into_iter()
+					.map(|key| format!("{}={}", key, self.format_val(key)))
+					.collect();
+				respond(
+					respond_to,
+					format!("sync: version={} {}", self.state_version, vals.join(" ")),
+				)
+			}
 
-			Message::GetVal { id, key } => self.get_session(&id)?.text(format!("{}: {}", key, self.get_val(&key))),
+			Message::GetState { respond_to } => respond(respond_to, format!("state: {}", self.keys_json())),
 
-			Message::SetVal { id, key, val } => {
-				self.get_session(&id)?;
+			Message::Snapshot { path, respond_to } => {
+				let response = match fs::write(&path, self.state_json().to_string()) {
+					Ok(..) => {
+						tracing::info!("Snapshot written to {}", path.display());
+						format!("snapshot: {}", path.display())
+					}
+					Err(err) => {
+						tracing::warn!("Unable to write snapshot to {}: {}", path.display(), err);
+						format!("error: unable to write snapshot: {}", err)
+					}
+				};
+				respond(respond_to, response)
+			}
+
+			Message::Pin { key, val, respond_to } => {
+				let response = match val {
+					Some(_) if self.disabled_keys.contains(&key) => "error: key disabled".to_owned(),
+					Some(val) => {
+						tracing::info!("Pinning \"{}\" to \"{}\"", key, val);
+						self.pending_reverts.remove(&key);
+						self.pinned_keys.insert(key.clone());
+						self.set_val(key, val, None);
+						"ok".to_owned()
+					}
+					None => {
+						tracing::info!("Unpinning \"{}\"", key);
+						self.pinned_keys.remove(&key);
+						"ok".to_owned()
+					}
+				};
+				respond(respond_to, response)
+			}
+
+			Message::SetKeyEnabled {
+				key,
+				enabled,
+				respond_to,
+			} => {
+				if enabled {
+					tracing::info!("Enabling \"{}\"", key);
+					if self.disabled_keys.remove(&key) {
+						// Catch sessions up on the value they haven't been notified of while it was disabled
+						self.notify_sessions(&key);
+					}
+				} else {
+					tracing::info!("Disabling \"{}\"", key);
+					self.disabled_keys.insert(key);
+				}
+				respond(respond_to, "ok".to_owned())
+			}
+
+			Message::TrackerUptime { respond_to } => {
+				let response = match self.tracker_since {
+					Some(since) => format!("tracker-uptime: {}", since.elapsed().as_secs()),
+					None => "tracker-uptime: none".to_owned(),
+				};
+				respond(respond_to, response)
+			}
+
+			Message::Broadcast { text, respond_to } => {
+				tracing::info!("Broadcasting operator message: {}", text);
+				self.send_line(format!("message: {}", text), |_, _| true);
+				respond(respond_to, "ok".to_owned())
+			}
+
+			Message::Events {
+				id,
+				enabled,
+				respond_to,
+			} => {
+				if enabled {
+					self.event_subscribers.insert(id);
+				} else {
+					self.event_subscribers.remove(&id);
+				}
+				respond(respond_to, "ok".to_owned())
+			}
+
+			Message::Mute {
+				id,
+				key,
+				muted,
+				respond_to,
+			} => {
+				let response = if !self.values.contains_key(&key) {
+					"error: unknown value key".to_owned()
+				} else {
+					let keys = self.muted_keys.entry(id).or_default();
+					if muted {
+						keys.insert(key);
+					} else {
+						keys.remove(&key);
+					}
+					"ok".to_owned()
+				};
+				respond(respond_to, response)
+			}
+
+			Message::GetSubscriptions { id, respond_to } => {
+				let muted = self.muted_keys.get(&id);
+				let (muted, subscribed): (Vec<&str>, Vec<&str>) = self
+					.values
+					.keys()
+					.map(String::as_str)
+					.partition(|key| muted.is_some_and(|keys| keys.contains(*key)));
+				let response = format!(
+					"subscriptions: keys={} muted={} events={}",
+					format_list(subscribed),
+					format_list(muted),
+					if self.event_subscribers.contains(&id) {
+						"on"
+					} else {
+						"off"
+					}
+				);
+				respond(respond_to, response)
+			}
+
+			// Only the parts of a session's state that the server keeps track of (the session adds the rest)
+			Message::GetSessionState { id, respond_to } => {
+				let role = if self.tracker_id == Some(id) {
+					"tracker"
+				} else if self.backups.contains(&id) {
+					"backup"
+				} else {
+					"observer"
+				};
+				let muted = self.muted_keys.get(&id).into_iter().flatten();
+				let rates = self
+					.rate_limits
+					.get(&id)
+					.into_iter()
+					.flatten()
+					.map(|(key, limit)| format!("{}:{:?}", key, limit.interval));
+				let response = format!(
+					"role={} muted={} rates={} events={}",
+					role,
+					format_list(muted),
+					format_list(rates),
+					if self.event_subscribers.contains(&id) {
+						"on"
+					} else {
+						"off"
+					}
+				);
+				respond(respond_to, response)
+			}
+
+			Message::Rate {
+				id,
+				key,
+				interval,
+				respond_to,
+			} => {
+				let response = if !self.values.contains_key(&key) {
+					"error: unknown value key".to_owned()
+				} else {
+					// Send the value the old limit was holding back now, since its scheduled flush won't send it once
+					// the limit is replaced
+					let limits = self.rate_limits.entry(id).or_default();
+					let pending = limits.get(&key).is_some_and(|limit| limit.pending);
+					match interval {
+						Some(interval) => {
+							let limit = RateLimit {
+								interval,
+								last_sent: pending.then(Instant::now),
+								pending: false,
+							};
+							limits.insert(key.clone(), limit);
+						}
+						None => {
+							limits.remove(&key);
+						}
+					}
+					if pending && self.wants_notification(id, &key) {
+						let line = self.format_line(&key);
+						self.send_line(line, |_, other_id| other_id == id);
+					}
+					"ok".to_owned()
+				};
+				respond(respond_to, response)
+			}
+
+			Message::FlushRate { id, key } => {
+				if let Some(limit) = self.rate_limits.get_mut(&id).and_then(|limits| limits.get_mut(&key)) {
+					if limit.pending {
+						limit.pending = false;
+						limit.last_sent = Some(Instant::now());
+						if self.wants_notification(id, &key) {
+							let line = self.format_line(&key);
+							self.send_line(line, |_, other_id| other_id == id);
+						}
+					}
+				}
+			}
+
+			Message::Revert { key, id } => {
+				// Only revert if the temporary value hasn't been replaced since
+				if self.pending_reverts.get(&key).is_some_and(|revert| revert.id == id) {
+					let PendingRevert { val, .. } = self.pending_reverts.remove(&key).unwrap();
+					if self.disabled_keys.contains(&key) {
+						tracing::info!(
+							"Temporary \"{}\" value expired, but the key is disabled - not reverting",
+							key
+						);
+					} else {
+						tracing::info!("Temporary \"{}\" value expired - reverting to \"{}\"", key, val);
+						self.set_val(key, val, None);
+					}
+				}
+			}
+
+			Message::Keepalive => {
+				let line = format!("keepalive {}", unix_millis(SystemTime::now()));
+				self.send_line(line, |_, _| true);
+			}
+
+			Message::Audit { id, command, response } => self.write_audit_record(id, &command, &response),
+
+			Message::PruneOriginWindows => {
+				let mut windows = self.origin_windows.lock().unwrap_or_else(PoisonError::into_inner);
+				windows.retain(|_, window| window.started.elapsed() < ORIGIN_WINDOW);
+			}
+
+			Message::FlushAuditLog => {
+				if let Some(audit_log) = &mut self.audit_log {
+					if let Err(err) = audit_log.flush() {
+						tracing::warn!("Unable to flush audit log: {}", err);
+					}
+				}
+			}
+
+			// Values from the watched file or a replay are set as if by the server itself
+			Message::InjectVals { vals } => {
+				for (key, val) in vals {
+					if self.pinned_keys.contains(&key) {
+						tracing::debug!("Ignoring injected value for pinned \"{}\"", key);
+						continue;
+					}
+					if self.disabled_keys.contains(&key) {
+						tracing::debug!("Ignoring injected value for disabled \"{}\"", key);
+						continue;
+					}
+					self.set_val(key, val, None);
+				}
+			}
 
-				// Make this session the tracker if there isn't one
-				if self.tracker_id == 0 {
-					self.tracker_id = id;
-					tracing::info!("Session {} promoted to tracker", id);
-					self.set_val(KEY_TRACKER.to_owned(), 1);
+			Message::FlushData => {
+				tracing::debug!("Rewriting all data files");
+				for (key, val) in &self.values {
+					self.write_val_file(key, *val);
 				}
+				self.write_state_file();
+				self.unwritten_keys.clear();
+			}
 
-				// If there is already a tracker, make sure it's this session
-				if self.tracker_id == id {
-					// Update the value and respond
-					self.set_val(key, val);
-					self.get_session(&id)?.text("ok".to_owned());
+			Message::WriteData => self.write_changed_vals(),
+
+			Message::Shutdown { respond_to } => {
+				tracing::info!("Shutting down");
+				self.write_changed_vals();
+				if let Some(audit_log) = &mut self.audit_log {
+					if let Err(err) = audit_log.flush() {
+						tracing::warn!("Unable to flush audit log: {}", err);
+					}
+				}
+				// Refuse any new connections, then wait for the existing sessions to disconnect once they're told to close
+				self.draining.store(true, Ordering::Relaxed);
+				self.close_all().await;
+				if self.sessions.is_empty() {
+					respond(respond_to, "ok".to_owned());
 				} else {
-					self.get_session(&id)?
-						.text("error: a tracker is already connected".to_owned());
+					self.shutdown = Some(respond_to);
 				}
 			}
 		};
@@ -133,141 +1318,4690 @@ impl ezsockets::ServerExt for HeartsockServer {
 }
 
 impl HeartsockServer {
-	fn get_val(&self, key: &String) -> &Value {
+	fn get_val(&self, key: &str) -> &Value {
 		self.values.get(key).expect("unknown value key")
 	}
 
-	/// Sets a value and notifies all non-tracker sessions
-	fn set_val(&mut self, key: String, val: Value) -> Value {
-		// Set the value and save the old value
-		let prev = self
-			.values
-			.insert(key.clone(), val)
-			.unwrap_or_else(|| panic!("no old value for key {}", key));
-
-		// If the new value is actually different, notify all other sessions of the change and write to its file
-		if prev != val {
-			tracing::debug!("Value \"{}\" changed to \"{}\" - notifying other sessions", key, val);
-			self.notify_sessions(&key, val);
-
-			if let Some(data_dir) = &self.data_dir {
-				let path = data_dir.join(format!("{}.txt", key));
-				match fs::write(&path, val.to_string()) {
-					Ok(..) => tracing::debug!("Value written to {}", path.display()),
-					Err(err) => tracing::warn!("Unable to write value to {}: {}", path.display(), err),
-				};
+	/// Formats a value for sending to sessions
+	fn format_val(&self, key: &str) -> String {
+		match &self.config.no_tracker_placeholder {
+			Some(placeholder) if self.tracker_id.is_none() && value_spec(key).is_some() => placeholder.clone(),
+			_ if self.config.distinguish_unset && value_spec(key).is_some() && !self.set_keys.contains(key) => {
+				"unset".to_owned()
 			}
+			_ => self.reported_val(key).to_string(),
 		}
+	}
 
-		prev
+	/// Gets the value of a key to report to sessions, which is its smoothed value if it's smoothed and the raw value
+	/// otherwise
+	fn reported_val(&self, key: &str) -> Value {
+		match self.smoothed.get(key) {
+			Some(smoothed) => smoothed.round() as Value,
+			None => *self.get_val(key),
+		}
 	}
 
-	/// Retrieves the session with a specific ID
-	fn get_session(&self, id: &u32) -> Result<&Session<u32, Message>, &'static str> {
-		self.sessions.get(id).ok_or("unknown session ID")
+	/// Formats a key's value as a key=value pair for a combined update line, including its sequence number (if
+	/// enabled)
+	fn format_pair(&self, key: &str) -> String {
+		if self.config.sequence_numbers {
+			let seq = self.sequences.get(key).copied().unwrap_or_default();
+			format!("{}={}#{}", key, self.format_val(key), seq)
+		} else {
+			format!("{}={}", key, self.format_val(key))
+		}
 	}
 
-	/// Notifies all non-tracker sessions of a value change
-	fn notify_sessions(&self, key: &str, val: Value) {
-		let sessions = self.sessions.iter().filter(|&(id, _)| *id != self.tracker_id);
-		for (_, session) in sessions {
-			session.text(format!("{}: {}", key, val));
+	/// Formats a key's value as a line to notify sessions with, including its sequence number and the session that set
+	/// it (if enabled)
+	fn format_line(&self, key: &str) -> String {
+		let mut line = format!("{}: {}", key, self.format_val(key));
+		if self.config.sequence_numbers {
+			let seq = self.sequences.get(key).copied().unwrap_or_default();
+			line.push_str(&format!(" #{}", seq));
+		}
+		if self.config.broadcast_source {
+			if let Some(source) = self.sources.get(key) {
+				line.push_str(&format!(" from={}", source));
+			}
 		}
+		line
 	}
-}
 
-pub struct HeartsockSession {
-	/// Unique ID of the session
-	id: SessionID,
-	/// Server this session is from
-	server: Server<HeartsockServer>,
-	/// Handle to use for communication with this session
-	handle: Session<SessionID, Message>,
-}
+	/// Sets a value on behalf of a session, promoting it to the tracker if needed, and returns the response for it
+	fn set_tracked_val(&mut self, id: SessionID, key: String, val: Value, options: SetOptions) -> String {
+		// Make sure the session didn't disconnect while the message was waiting to be handled
+		if !self.sessions.contains_key(&id) {
+			return "error: unknown session".to_owned();
+		}
+		if !self.is_from_tracker_ip(id) {
+			return "error: not from the tracker's IP".to_owned();
+		}
+		if self.disabled_keys.contains(&key) {
+			return "error: key disabled".to_owned();
+		}
+		if let Some(priority) = options.priority {
+			self.priorities.insert(id, priority);
+		}
 
-#[async_trait]
-impl ezsockets::SessionExt for HeartsockSession {
-	type ID = SessionID;
-	type Args = ();
-	type Call = Message;
+		// Calls are handled one at a time, so when several sessions try to set a value while there's no tracker,
+		// whichever call is handled first promotes its session and all of the others are rejected
+		match self.tracker_id {
+			// Make this session the tracker if there isn't one (unless it needs to be claimed first)
+			None if self.config.explicit_tracker => return "error: no tracker claimed".to_owned(),
+			None => self.promote_tracker(id),
 
-	// Get the ID of the session
-	fn id(&self) -> &Self::ID {
-		&self.id
-	}
+			// If there is already a tracker, make sure it's this session
+			Some(tracker_id) if tracker_id == id => {}
 
-	// Text received from client
-	async fn on_text(&mut self, text: String) -> Result<(), ezsockets::Error> {
-		let cmd = text.to_lowercase();
+			// Take over from the tracker if this session outranks it (unless the role needs to be claimed)
+			Some(tracker_id) if !self.config.explicit_tracker && self.outranks(id, tracker_id) => {
+				self.promote_tracker(id)
+			}
+			Some(_) => return "error: a tracker is already connected".to_owned(),
+		}
 
-		match cmd.as_str() {
-			// Handle setting values
-			cmd if cmd.starts_with("set") => {
-				let parts: Vec<&str> = cmd.split_whitespace().collect();
-				let key = parts[1];
+		// Pinned keys keep their value until they're unpinned
+		if self.pinned_keys.contains(&key) {
+			return "ok (pinned, not applied)".to_owned();
+		}
 
-				if matches!(key, KEY_BPM | KEY_BATTERY) {
-					let val = parts[2].parse::<Value>();
-					match val {
-						Ok(val) => self.server.call(Message::SetVal {
-							id: self.id,
-							key: key.to_owned(),
-							val,
-						}),
-						Err(_) => self.handle.text(format!("error: unknown input for {} value", key)),
-					}
-				} else {
-					self.handle.text("error: unknown value key".to_owned())
-				}
+		// Reject implausible jumps from the current value (there's nothing to compare to if it's never been set)
+		if let Some(max_delta) = self.config.max_deltas.get(&key) {
+			if self.set_keys.contains(&key) && self.get_val(&key).abs_diff(val) > *max_delta {
+				tracing::debug!("Rejecting implausible change of \"{}\" to \"{}\"", key, val);
+				return "error: implausible change".to_owned();
 			}
+		}
 
-			// Handle getting values
-			cmd if cmd.starts_with("get") => {
-				let parts: Vec<&str> = cmd.split_whitespace().collect();
-				let key = parts[1];
-				self.server.call(Message::GetVal {
-					id: self.id,
-					key: key.to_owned(),
-				})
+		// Reject readings older than the latest one (if enabled and the set has a timestamp)
+		if let Some(timestamp) = options.timestamp.filter(|_| self.config.monotonic_timestamps) {
+			if self.timestamps.get(&key).is_some_and(|latest| timestamp < *latest) {
+				tracing::debug!("Rejecting stale update of \"{}\" to \"{}\"", key, val);
+				return "error: stale update".to_owned();
 			}
+			self.timestamps.insert(key.clone(), timestamp);
+		}
 
-			"ping" => self.server.call(Message::Ping { id: self.id }),
-			_ => self.handle.text("error: unknown input".to_owned()),
+		self.set_temporary_val(key, val, options.ttl, id);
+		"ok".to_owned()
+	}
+
+	/// Sets a value that reverts to the value from before it once its TTL expires (if it has one)
+	fn set_temporary_val(&mut self, key: String, val: Value, ttl: Option<Duration>, source: SessionID) {
+		match ttl {
+			Some(ttl) => {
+				// Keep reverting to the original value if a temporary value replaces another one
+				let revert_val = match self.pending_reverts.get(&key) {
+					Some(revert) => revert.val,
+					None => *self.get_val(&key),
+				};
+				self.latest_revert_id += 1;
+				let revert = PendingRevert {
+					id: self.latest_revert_id,
+					val: revert_val,
+				};
+				self.pending_reverts.insert(key.clone(), revert);
+
+				let server = self.handle.clone();
+				let key = key.clone();
+				tokio::spawn(async move {
+					tokio::time::sleep(ttl).await;
+					server.call(Message::Revert { key, id: revert.id });
+				});
+			}
+			None => {
+				self.pending_reverts.remove(&key);
+			}
 		}
 
-		Ok(())
+		self.set_val(key, val, Some(source));
 	}
 
-	// Binary data received from client
-	async fn on_binary(&mut self, _bytes: Vec<u8>) -> Result<(), ezsockets::Error> {
-		tracing::debug!("Received binary data (unsupported) from session {}", self.id);
-		self.handle.text("error: binary data unsupported".to_owned());
-		Ok(())
+	/// Makes a session the tracker if there isn't one or it outranks the current one, and returns the response for
+	/// the claim
+	fn claim_tracker(&mut self, id: SessionID, priority: Option<Priority>) -> String {
+		if !self.sessions.contains_key(&id) {
+			return "error: unknown session".to_owned();
+		}
+		if !self.is_from_tracker_ip(id) {
+			return "error: not from the tracker's IP".to_owned();
+		}
+		if let Some(priority) = priority {
+			self.priorities.insert(id, priority);
+		}
+
+		match self.tracker_id {
+			Some(tracker_id) if tracker_id == id => "ok".to_owned(),
+			Some(tracker_id) if !self.outranks(id, tracker_id) => "error: a tracker is already connected".to_owned(),
+			_ => {
+				self.promote_tracker(id);
+				"ok".to_owned()
+			}
+		}
 	}
 
-	// Unused
-	async fn on_call(&mut self, _call: Self::Call) -> Result<(), ezsockets::Error> {
-		Ok(())
+	/// Adds a session to the end of the line of backups that take over the tracker role if it's lost, and returns the
+	/// response for the claim
+	fn claim_backup(&mut self, id: SessionID) -> String {
+		if !self.sessions.contains_key(&id) {
+			return "error: unknown session".to_owned();
+		}
+		if !self.is_from_tracker_ip(id) {
+			return "error: not from the tracker's IP".to_owned();
+		}
+
+		if self.tracker_id == Some(id) {
+			"error: already the tracker".to_owned()
+		} else {
+			if !self.backups.contains(&id) {
+				tracing::info!(
+					"Session {} registered as tracker backup #{}",
+					id,
+					self.backups.len() + 1
+				);
+				self.backups.push(id);
+			}
+			"ok".to_owned()
+		}
+	}
+
+	/// Checks whether a session is from the IP the tracker role is pinned to (always the case if it isn't pinned yet)
+	fn is_from_tracker_ip(&self, id: SessionID) -> bool {
+		match (self.tracker_ip, self.addresses.get(&id)) {
+			(Some(tracker_ip), Some(address)) => address.ip() == tracker_ip,
+			_ => true,
+		}
+	}
+
+	/// Checks whether a session has a higher tracker priority than another (equal priorities don't count)
+	fn outranks(&self, id: SessionID, other_id: SessionID) -> bool {
+		let priority = |id| self.priorities.get(&id).copied().unwrap_or_default();
+		priority(id) > priority(other_id)
+	}
+
+	/// Makes a session the tracker, handing the role off from the current tracker if there is one
+	fn promote_tracker(&mut self, id: SessionID) {
+		self.backups.retain(|backup_id| *backup_id != id);
+		self.tracker_grant = None;
+
+		// Pin the tracker role to the first tracker's IP (if enabled), dropping any backups that registered from other
+		// IPs before it was pinned so they can't be failed over to
+		if self.config.pin_tracker_ip && self.tracker_ip.is_none() {
+			if let Some(address) = self.addresses.get(&id) {
+				tracing::info!("Pinning tracker role to IP {}", address.ip());
+				self.tracker_ip = Some(address.ip());
+
+				let (backups, dropped): (Vec<SessionID>, Vec<SessionID>) = self
+					.backups
+					.iter()
+					.partition(|backup_id| self.is_from_tracker_ip(**backup_id));
+				for backup_id in dropped {
+					tracing::info!(
+						"Dropping tracker backup session {} (not from the tracker's IP)",
+						backup_id
+					);
+				}
+				self.backups = backups;
+			}
+		}
+
+		// Each tracker's timestamps come from its own clock
+		if self.tracker_id != Some(id) {
+			self.timestamps.clear();
+		}
+
+		self.tracker_since = Some(Instant::now());
+		match self.tracker_id.replace(id) {
+			Some(prev_id) => tracing::info!("Tracker handed off from session {} to session {}", prev_id, id),
+			None => {
+				tracing::info!("Session {} promoted to tracker", id);
+
+				// Replace the placeholders with the real values
+				if self.config.no_tracker_placeholder.is_some() {
+					self.notify_settable_vals();
+				}
+			}
+		}
+		self.set_val(KEY_TRACKER.to_owned(), 1, Some(id));
+		self.check_observers(true);
+	}
+
+	/// Releases the tracker role if a session has it, returning whether it did
+	fn release_tracker(&mut self, id: SessionID) -> bool {
+		if self.tracker_id != Some(id) {
+			return false;
+		}
+
+		self.tracker_id = None;
+		self.tracker_since = None;
+
+		// Hand the role straight to the first backup in line if there is one, so there's never a gap without a tracker
+		if !self.backups.is_empty() {
+			let backup_id = self.backups.remove(0);
+			tracing::info!("Failing over to backup session {}", backup_id);
+			self.promote_tracker(backup_id);
+			self.send_line("promoted".to_owned(), |_, other_id| other_id == backup_id);
+			return true;
+		}
+
+		self.set_val(KEY_TRACKER.to_owned(), 0, None);
+
+		// Replace the real values with placeholders until there's a tracker again
+		if self.config.no_tracker_placeholder.is_some() {
+			self.notify_settable_vals();
+		}
+		self.check_observers(false);
+
+		true
+	}
+
+	/// Lets the tracker know if there stopped being or started being other sessions (if enabled), or whether there are
+	/// any regardless if it just became the tracker
+	fn check_observers(&mut self, new_tracker: bool) {
+		if !self.config.observer_events {
+			return;
+		}
+
+		let has_observers = self.sessions.keys().any(|id| Some(*id) != self.tracker_id);
+		if has_observers == self.has_observers && !new_tracker {
+			return;
+		}
+		self.has_observers = has_observers;
+
+		if let Some(tracker_id) = self.tracker_id {
+			let line = if has_observers {
+				"observers-present"
+			} else {
+				"no-observers"
+			};
+			self.send_line(line.to_owned(), |_, id| id == tracker_id);
+		}
+	}
+
+	/// Sets a value on behalf of a session (or the server itself if there's no source) and notifies all non-tracker
+	/// sessions, returning the previous value (if the key had one)
+	fn set_val(&mut self, key: String, val: Value, source: Option<SessionID>) -> Option<Value> {
+		// Set the value and save the old value
+		let prev = self.values.insert(key.clone(), val);
+		self.set_at.insert(key.clone(), Instant::now());
+		let first_set = self.set_keys.insert(key.clone());
+
+		// Keep track of which session set the value, by the ID shown to clients
+		match source.and_then(|id| self.public_ids.get(&id)) {
+			Some(public_id) => self.sources.insert(key.clone(), public_id.clone()),
+			None => self.sources.remove(&key),
+		};
+
+		// Keep track of the peaks of settable values
+		if value_spec(&key).is_some() {
+			self.peaks
+				.entry(key.clone())
+				.and_modify(|peaks| {
+					peaks.min = peaks.min.min(val);
+					peaks.max = peaks.max.max(val);
+				})
+				.or_insert(Peaks { min: val, max: val });
+		}
+
+		// If the new value is actually different (or is no longer unset), or its smoothed value is, notify all other
+		// sessions of the change
+		let changed = prev != Some(val) || (first_set && self.config.distinguish_unset);
+		let smoothed_changed = self.smooth_val(&key, val, source.is_none());
+		if changed || smoothed_changed {
+			self.state_version += 1;
+			*self.sequences.entry(key.clone()).or_default() += 1;
+
+			if self.config.broadcast_intervals.contains_key(&key) {
+				tracing::debug!(
+					"Value \"{}\" changed to \"{}\" - waiting to notify other sessions",
+					key,
+					val
+				);
+				self.pending_broadcasts.insert(key.clone());
+			} else {
+				tracing::debug!("Value \"{}\" changed to \"{}\" - notifying other sessions", key, val);
+				self.notify_sessions(&key);
+			}
+		}
+
+		// Write the raw value to its file if it changed (or leave it for the next batch if writes are batched)
+		if changed {
+			let now = SystemTime::now();
+			self.updated_at.insert(key.clone(), now);
+			if self.config.data_write_interval.is_some() {
+				self.unwritten_keys.insert(key.clone());
+			} else {
+				self.write_val_file(&key, val);
+				self.write_state_file();
+			}
+			self.write_jsonl_record(&key, val, now);
+		}
+
+		prev
+	}
+
+	/// Updates the smoothed value of a key with a new raw value (if the key is smoothed), returning whether the value
+	/// reported to sessions changed. Values that don't come from a session (pinned, reverted, or injected ones) reset
+	/// the average so they're reported exactly.
+	fn smooth_val(&mut self, key: &str, val: Value, reset: bool) -> bool {
+		let alpha = match self.config.smoothing.get(key) {
+			Some(alpha) => *alpha,
+			None => return false,
+		};
+
+		let prev = self.smoothed.get(key).copied();
+		let smoothed = match prev {
+			Some(prev) if !reset => alpha * f64::from(val) + (1.0 - alpha) * prev,
+			_ => f64::from(val),
+		};
+		self.smoothed.insert(key.to_owned(), smoothed);
+		prev.map(f64::round) != Some(smoothed.round())
+	}
+
+	/// Writes a value to its file in the data directory (if there is one)
+	fn write_val_file(&self, key: &str, val: Value) {
+		if self.config.data_format == DataFormat::Json {
+			return;
+		}
+
+		if let Some(data_dir) = &self.config.data_dir {
+			let path = data_dir.join(format!("{}.txt", key));
+			match fs::write(&path, val.to_string()) {
+				Ok(..) => tracing::debug!("Value written to {}", path.display()),
+				Err(err) => tracing::warn!("Unable to write value to {}: {}", path.display(), err),
+			};
+		}
+	}
+
+	/// Closes all sessions because the server is shutting down
+	async fn close_all(&self) {
+		tracing::info!("Closing all {} remaining sessions", self.sinks.len());
+
+		// Sending to a sink whose connection has already ended would panic (the session is cleaned up once it
+		// disconnects anyway)
+		for sink in self.sinks.values().filter(|sink| !sink.is_closed()) {
+			let frame = CloseFrame {
+				code: CloseCode::Away,
+				reason: "server is shutting down".to_owned(),
+			};
+			sink.send(ezsockets::Message::Close(Some(frame))).await;
+		}
+	}
+
+	/// Writes the values of all keys that have changed since they were last written to the data directory
+	fn write_changed_vals(&mut self) {
+		if self.unwritten_keys.is_empty() {
+			return;
+		}
+
+		tracing::debug!("Writing {} changed values", self.unwritten_keys.len());
+		for key in &self.unwritten_keys {
+			if let Some(val) = self.values.get(key) {
+				self.write_val_file(key, *val);
+			}
+		}
+		self.write_state_file();
+		self.unwritten_keys.clear();
+	}
+
+	/// Builds the JSON representation of all values, when they were last updated, and whether there's a tracker
+	fn state_json(&self) -> serde_json::Value {
+		// Timestamps are in milliseconds since the Unix epoch, or null for values that have never been set
+		let updated_at: serde_json::Map<String, serde_json::Value> = self
+			.values
+			.keys()
+			.map(|key| {
+				let updated_at = self.updated_at.get(key).map(|time| unix_millis(*time));
+				(key.clone(), updated_at.into())
+			})
+			.collect();
+		serde_json::json!({
+			"tracker": self.tracker_id.is_some(),
+			"values": self.values,
+			"updated_at": updated_at,
+		})
+	}
+
+	/// Checks whether a key's value is stale (a value trackers set while there's no tracker to keep it current, or that
+	/// hasn't been updated within the configured time)
+	fn is_stale(&self, key: &str) -> bool {
+		if value_spec(key).is_none() {
+			return false;
+		}
+
+		let outdated = |stale_after: Duration| match self.set_at.get(key) {
+			Some(set_at) => set_at.elapsed() > stale_after,
+			None => true,
+		};
+		self.tracker_id.is_none() || self.config.stale_after.is_some_and(outdated)
+	}
+
+	/// Builds the JSON representation of each key's value, unit, when it was last updated, and whether it's stale
+	fn keys_json(&self) -> serde_json::Value {
+		let keys: serde_json::Map<String, serde_json::Value> = self
+			.values
+			.keys()
+			.map(|key| {
+				let spec = value_spec(key);
+				let updated_at = self.updated_at.get(key).map(|time| unix_millis(*time));
+				let entry = serde_json::json!({
+					"value": self.reported_val(key),
+					"unit": spec.map(|spec| spec.unit),
+					"updated_at": updated_at,
+					"stale": self.is_stale(key),
+				});
+				(key.clone(), entry)
+			})
+			.collect();
+		keys.into()
+	}
+
+	/// Writes all values to state.json in the data directory (if there is one and it's enabled)
+	fn write_state_file(&self) {
+		let data_dir = match &self.config.data_dir {
+			Some(data_dir) if self.config.data_format != DataFormat::Text => data_dir,
+			_ => return,
+		};
+
+		// Write to a temporary file first and then move it into place, so readers never see a partial file
+		let path = data_dir.join("state.json");
+		let tmp_path = data_dir.join("state.json.tmp");
+		let result = fs::write(&tmp_path, self.state_json().to_string()).and_then(|_| fs::rename(&tmp_path, &path));
+		match result {
+			Ok(..) => tracing::debug!("State written to {}", path.display()),
+			Err(err) => tracing::warn!("Unable to write state to {}: {}", path.display(), err),
+		};
+	}
+
+	/// Writes a JSON Lines record of a value change to stdout (if enabled)
+	fn write_jsonl_record(&mut self, key: &str, val: Value, time: SystemTime) {
+		let tracker = self.tracker_id.is_some();
+		let jsonl_out = match &mut self.jsonl_out {
+			Some(jsonl_out) => jsonl_out,
+			None => return,
+		};
+
+		let record = serde_json::json!({
+			"key": key,
+			"value": val,
+			"ts": unix_millis(time),
+			"tracker": tracker,
+		});
+		if let Err(err) = writeln!(jsonl_out, "{}", record) {
+			tracing::warn!("Unable to write value change to stdout: {}", err);
+		}
+	}
+
+	/// Writes a record of a set command and its response to the audit log (if there is one)
+	fn write_audit_record(&mut self, id: SessionID, command: &str, response: &str) {
+		let audit_log = match &mut self.audit_log {
+			Some(audit_log) => audit_log,
+			None => return,
+		};
+
+		let record = serde_json::json!({
+			"ts": unix_millis(SystemTime::now()),
+			"session": id,
+			"address": self.addresses.get(&id).map(|address| address.to_string()),
+			"command": command,
+			"response": response,
+			"accepted": response == "ok",
+		});
+		if let Err(err) = writeln!(audit_log, "{}", record) {
+			tracing::warn!("Unable to write to audit log: {}", err);
+		}
+	}
+
+	/// Notifies all non-tracker sessions of the latest values of keys that have changed since their last broadcast,
+	/// combining them into a single line for each session (if enabled)
+	fn flush_broadcasts(&mut self, keys: &[String]) {
+		let keys: Vec<&String> = keys.iter().filter(|key| self.pending_broadcasts.remove(*key)).collect();
+		if keys.is_empty() {
+			return;
+		}
+		tracing::debug!("Notifying other sessions of latest {:?} values", keys);
+
+		if !self.config.combine_broadcasts || keys.len() == 1 {
+			for key in keys {
+				self.notify_sessions(key);
+			}
+			return;
+		}
+
+		// Each session gets only the keys it wants (and hasn't rate-limited), on a single line if there's more than one
+		self.send_lines(|server, id| {
+			let keys: Vec<&&String> = keys
+				.iter()
+				.filter(|key| server.wants_notification(id, key) && !server.is_rate_limited(id, key))
+				.collect();
+			match keys[..] {
+				[] => None,
+				[key] => Some(server.format_line(key)),
+				_ => {
+					let vals: Vec<String> = keys.iter().map(|key| server.format_pair(key)).collect();
+					Some(format!("update {}", vals.join(" ")))
+				}
+			}
+		});
+		for key in keys {
+			self.notify_rate_limited_sessions(key);
+		}
+	}
+
+	/// Notifies all non-tracker sessions of the current values of all settable keys
+	fn notify_settable_vals(&mut self) {
+		for spec in &VALUE_SPECS {
+			self.notify_sessions(spec.key);
+		}
+	}
+
+	/// Notifies all non-tracker sessions of a key's current value
+	fn notify_sessions(&mut self, key: &str) {
+		let line = self.format_line(key);
+		self.send_line(line, |server, id| {
+			server.wants_notification(id, key) && !server.is_rate_limited(id, key)
+		});
+		self.notify_rate_limited_sessions(key);
+	}
+
+	/// Notifies sessions that have limited how often they're notified of a key of its current value on their own
+	/// schedules
+	fn notify_rate_limited_sessions(&mut self, key: &str) {
+		let line = self.format_line(key);
+		let limited: Vec<SessionID> = self
+			.rate_limits
+			.keys()
+			.copied()
+			.filter(|id| self.wants_notification(*id, key) && self.is_rate_limited(*id, key))
+			.collect();
+		for id in limited {
+			self.notify_rate_limited(id, key, &line);
+		}
+	}
+
+	/// Checks whether a session should be notified of changes to a key (it isn't the tracker, the key isn't disabled, and
+	/// the session hasn't muted it)
+	fn wants_notification(&self, id: SessionID, key: &str) -> bool {
+		Some(id) != self.tracker_id
+			&& !self.disabled_keys.contains(key)
+			&& !self.muted_keys.get(&id).is_some_and(|keys| keys.contains(key))
+	}
+
+	/// Checks whether a session has limited how often it's notified of changes to a key
+	fn is_rate_limited(&self, id: SessionID, key: &str) -> bool {
+		self.rate_limits.get(&id).is_some_and(|limits| limits.contains_key(key))
+	}
+
+	/// Notifies a rate-limited session of a key's value if enough time has passed since the last notification, or
+	/// schedules a notification of the latest value for when it has
+	fn notify_rate_limited(&mut self, id: SessionID, key: &str, line: &str) {
+		let limit = match self.rate_limits.get_mut(&id).and_then(|limits| limits.get_mut(key)) {
+			Some(limit) if !limit.pending => limit,
+			_ => return,
+		};
+
+		let now = Instant::now();
+		match limit.last_sent {
+			Some(last_sent) if now.duration_since(last_sent) < limit.interval => {
+				limit.pending = true;
+				let delay = limit.interval - now.duration_since(last_sent);
+				let server = self.handle.clone();
+				let key = key.to_owned();
+				tokio::spawn(async move {
+					tokio::time::sleep(delay).await;
+					server.call(Message::FlushRate { id, key });
+				});
+			}
+			_ => {
+				limit.last_sent = Some(now);
+				self.send_line(line.to_owned(), |_, other_id| other_id == id);
+			}
+		}
+	}
+
+	/// Sends a line of text to all sessions that a filter accepts
+	fn send_line<F>(&mut self, line: String, filter: F)
+	where
+		F: Fn(&Self, SessionID) -> bool,
+	{
+		self.send_lines(|server, id| filter(server, id).then(|| line.clone()));
+	}
+
+	/// Sends each session the line of text built for it (if any)
+	fn send_lines<F>(&mut self, build: F)
+	where
+		F: Fn(&Self, SessionID) -> Option<String>,
+	{
+		let mut dead = Vec::new();
+
+		for (id, session) in &self.sessions {
+			let line = match build(self, *id) {
+				Some(line) => line,
+				None => continue,
+			};
+
+			// Sending to a session that has closed would panic, so skip it and clean it up instead
+			if session.alive() {
+				session.text(line);
+			} else {
+				dead.push(*id);
+			}
+		}
+
+		for id in dead {
+			tracing::info!("Session {} closed before it could be notified - removing it", id);
+			self.remove_session(id);
+		}
+	}
+
+	/// Sends a connect/disconnect event to all subscribed sessions
+	fn send_event(&self, event: String) {
+		let sessions = self
+			.event_subscribers
+			.iter()
+			.filter_map(|id| self.sessions.get(id))
+			.filter(|session| session.alive());
+		for session in sessions {
+			session.text(event.clone());
+		}
+	}
+
+	/// Removes a session from the server, returning whether it hadn't already been removed
+	fn remove_session(&mut self, id: SessionID) -> bool {
+		self.logs.stop(&id);
+		self.priorities.remove(&id);
+		self.backups.retain(|backup_id| *backup_id != id);
+		self.event_subscribers.remove(&id);
+		self.muted_keys.remove(&id);
+		self.rate_limits.remove(&id);
+		self.device_ids.remove(&id);
+		self.addresses.remove(&id);
+		self.sinks.remove(&id);
+		let public_id = self.public_ids.remove(&id);
+		let removed = self.sessions.remove(&id).is_some();
+		if let (true, Some(public_id)) = (removed, public_id) {
+			self.send_event(format!("disconnected {}", public_id));
+		}
+		removed
 	}
 }
 
-/// Create and run a Heartsock websocket server
-pub async fn run<A>(address: A, data_dir: Option<PathBuf>) -> Result<(), ezsockets::Error>
-where
-	A: ToSocketAddrs + Display,
-{
-	tracing::info!("WebSocket server starting on {}", address);
-	let (server, _) = ezsockets::Server::create(|handle| HeartsockServer {
-		sessions: HashMap::new(),
-		handle,
-		data_dir,
-		latest_id: 0,
-		tracker_id: 0,
-		values: HashMap::from([
-			(KEY_TRACKER.to_owned(), 0),
-			(KEY_BPM.to_owned(), 0),
-			(KEY_BATTERY.to_owned(), 0),
-		]),
-	});
-	ezsockets::tungstenite::run(server, address, |_socket| async move { Ok(()) }).await
+pub struct HeartsockSession {
+	/// Unique ID of the session
+	id: SessionID,
+	/// Server this session is from
+	server: Server<HeartsockServer>,
+	/// Handle to use for communication with this session
+	handle: Session<SessionID, Message>,
+	/// Configuration of the server this session is from
+	config: Arc<ServerConfig>,
+	/// Forwarder to register with for tailing the logs
+	logs: LogForwarder,
+	/// Whether the session has authenticated as an admin
+	admin: bool,
+	/// Keys the session is permitted to set by the access control tokens it has authenticated with
+	permitted_keys: HashSet<String>,
+	/// Whether responses are prefixed with the command they're for
+	echo: bool,
+	/// Whether the session is only allowed to read values
+	readonly: bool,
+	/// Captured headers from the client's upgrade request
+	headers: Vec<(String, String)>,
+	/// Origin of the client (if it's a browser)
+	origin: Option<String>,
+	/// Command windows of each origin, shared with all other sessions
+	origin_windows: OriginWindows,
+	/// Address of the client, as seen by the server
+	address: SocketAddr,
+	/// ID shown to the client (which differs from the sequential ID if it's opaque)
+	public_id: String,
+	/// Sequence number of each key when the session connected
+	connect_sequences: HashMap<String, u64>,
+	/// Whether the session hasn't sent anything yet (so it can still send a handshake)
+	first_message: bool,
+	/// Whether successful sets go unacknowledged
+	quiet: bool,
+	/// Numbers of errors the session has run into
+	errors: ErrorCounts,
+}
+
+#[async_trait]
+impl ezsockets::SessionExt for HeartsockSession {
+	type ID = SessionID;
+	type Args = SessionArgs;
+	type Call = Message;
+
+	// Get the ID of the session
+	fn id(&self) -> &Self::ID {
+		&self.id
+	}
+
+	// Text received from client
+	async fn on_text(&mut self, text: String) -> Result<(), ezsockets::Error> {
+		let mut cmd = normalize_command(&text, self.config.case_sensitive_args);
+		let first_message = std::mem::take(&mut self.first_message);
+
+		// Old clients send just the heart rate
+		if self.config.legacy_protocol && cmd.parse::<Value>().is_ok() {
+			cmd = format!("set bpm {}", cmd);
+		}
+
+		let response = match cmd.as_str() {
+			// Sessions from an origin that has sent too many commands have to wait until its next window
+			_ if self.origin_limited() => RATE_LIMITED.to_owned(),
+
+			// Commands with control characters or unexpected bytes are rejected outright (if enabled)
+			_ if self.config.ascii_commands && !is_printable_ascii(text.trim()) => {
+				"error: invalid command encoding".to_owned()
+			}
+
+			// Clients can set all of their options at once with a JSON handshake object as their first message
+			_ if first_message && is_handshake(&text) => self.handshake(&text).await,
+
+			// Sessions from the read-only listener can't change anything or gain any privileges
+			cmd if self.readonly
+				&& (cmd.starts_with("set")
+					|| cmd.starts_with("cas")
+					|| cmd.starts_with("claim")
+					|| cmd.starts_with("auth")
+					|| cmd.starts_with("device-id")) =>
+			{
+				"error: read-only connection".to_owned()
+			}
+
+			// Handle setting values
+			cmd if cmd.starts_with("set") => {
+				let parts: Vec<&str> = cmd.split_whitespace().skip(1).collect();
+				match self.split_key(&parts) {
+					Some((key, _)) if !self.may_set(key) => "error: not permitted for key".to_owned(),
+					Some((key, [val, options @ ..])) => match (val.parse::<Value>(), parse_set_options(options)) {
+						(Ok(val), Ok(options)) => {
+							self.server
+								.call_with(|respond_to| Message::SetVal {
+									id: self.id,
+									key: key.to_owned(),
+									val,
+									options,
+									respond_to,
+								})
+								.await
+						}
+						(Err(_), _) => format!("error: unknown input for {} value", key),
+						(_, Err(err)) => format!("error: {}", err),
+					},
+					Some((key, [])) => format!("error: unknown input for {} value", key),
+					None => "error: unknown value key".to_owned(),
+				}
+			}
+
+			// Handle setting values only if they currently have an expected value
+			cmd if cmd.starts_with("cas") => {
+				let parts: Vec<&str> = cmd.split_whitespace().collect();
+				match parts[..] {
+					[_, key, _, _] if value_spec(key).is_some() && !self.may_set(key) => {
+						"error: not permitted for key".to_owned()
+					}
+					[_, key, expected, val] if value_spec(key).is_some() => {
+						match (expected.parse::<Value>(), val.parse::<Value>()) {
+							(Ok(expected), Ok(val)) => {
+								self.server
+									.call_with(|respond_to| Message::Cas {
+										id: self.id,
+										key: key.to_owned(),
+										expected,
+										val,
+										respond_to,
+									})
+									.await
+							}
+							_ => format!("error: unknown input for {} value", key),
+						}
+					}
+					[_, _, _, _] => "error: unknown value key".to_owned(),
+					_ => "error: usage: cas <key> <expected> <new>".to_owned(),
+				}
+			}
+
+			// Handle getting values
+			cmd if cmd.starts_with("get") => {
+				let key = cmd.split_whitespace().nth(1).or(self.config.default_key.as_deref());
+				match key {
+					Some(key) => {
+						let response = self
+							.server
+							.call_with(|respond_to| Message::GetVal {
+								key: key.to_owned(),
+								respond_to,
+							})
+							.await;
+
+						// Old clients expect just the value
+						match response.split_once(": ") {
+							Some((_, val)) if self.config.legacy_protocol && !response.starts_with("error") => {
+								val.to_owned()
+							}
+							_ => response,
+						}
+					}
+					None => "error: unknown value key".to_owned(),
+				}
+			}
+
+			// Handle getting the lowest and highest values of a key
+			cmd if cmd.starts_with("peaks") => match cmd.split_whitespace().nth(1) {
+				Some(key) if value_spec(key).is_some() => {
+					self.server
+						.call_with(|respond_to| Message::GetPeaks {
+							key: key.to_owned(),
+							respond_to,
+						})
+						.await
+				}
+				_ => "error: unknown value key".to_owned(),
+			},
+
+			// Handle getting when a value last changed
+			cmd if cmd.starts_with("updated") => match cmd.split_whitespace().nth(1) {
+				Some(key) => {
+					self.server
+						.call_with(|respond_to| Message::GetUpdated {
+							key: key.to_owned(),
+							respond_to,
+						})
+						.await
+				}
+				None => "error: usage: updated <key>".to_owned(),
+			},
+
+			// Handle listing the keys whose values are stale
+			"stale" => {
+				self.server
+					.call_with(|respond_to| Message::GetStale { respond_to })
+					.await
+			}
+
+			// Handle getting how close the server is to its session limit
+			"capacity" => {
+				self.server
+					.call_with(|respond_to| Message::GetCapacity { respond_to })
+					.await
+			}
+
+			// Handle getting how many times a key has changed since the server started (or this session connected)
+			cmd if cmd.starts_with("change-count") => {
+				let parts: Vec<&str> = cmd.split_whitespace().collect();
+				let (key, baseline) = match parts[..] {
+					[_, key] => (key, Some(0)),
+					[_, key, "since-connect"] => {
+						(key, Some(self.connect_sequences.get(key).copied().unwrap_or_default()))
+					}
+					[_, key, ..] => (key, None),
+					_ => ("", None),
+				};
+				match baseline {
+					Some(baseline) => {
+						self.server
+							.call_with(|respond_to| Message::GetChangeCount {
+								key: key.to_owned(),
+								baseline,
+								respond_to,
+							})
+							.await
+					}
+					None => "error: usage: change-count <key> [since-connect]".to_owned(),
+				}
+			}
+
+			// Handle checking whether a key is currently being set by a tracker
+			cmd if cmd.starts_with("has-tracker") => match cmd.split_whitespace().nth(1) {
+				Some(key) => {
+					self.server
+						.call_with(|respond_to| Message::HasTracker {
+							key: key.to_owned(),
+							respond_to,
+						})
+						.await
+				}
+				None => "error: usage: has-tracker <key>".to_owned(),
+			},
+
+			"rejections" | "reset-rejections" => {
+				if !self.admin {
+					"error: not authorized".to_owned()
+				} else if cmd == "rejections" {
+					self.server
+						.call_with(|respond_to| Message::GetRejections { respond_to })
+						.await
+				} else {
+					self.server
+						.call_with(|respond_to| Message::ResetRejections { respond_to })
+						.await
+				}
+			}
+
+			"reset-stats" => {
+				if self.admin {
+					self.server
+						.call_with(|respond_to| Message::ResetStats { respond_to })
+						.await
+				} else {
+					"error: not authorized".to_owned()
+				}
+			}
+
+			// Handle authenticating as an admin (the token is taken from the original text to preserve its case)
+			cmd if cmd.starts_with("auth") => {
+				let token = text.split_whitespace().nth(1);
+				let acl_keys = token.and_then(|token| self.config.acl.get(token));
+				match (&self.config.admin_token, token, acl_keys) {
+					(None, _, _) if self.config.acl.is_empty() => "error: authentication is disabled".to_owned(),
+					(Some(admin_token), Some(token), _) if admin_token == token => {
+						self.admin = true;
+						tracing::info!("Session {} authenticated as an admin", self.id);
+						"ok".to_owned()
+					}
+					(_, _, Some(keys)) => {
+						self.permitted_keys.extend(keys.iter().cloned());
+						tracing::info!("Session {} authenticated to set {:?}", self.id, keys);
+						"ok".to_owned()
+					}
+					_ => "error: invalid token".to_owned(),
+				}
+			}
+
+			// Handle reporting the client's address
+			"whereami" => format!("address: {}", self.address),
+
+			// Handle describing the session
+			"info" => format!("id: {}{}", self.public_id, format_headers(&self.headers)),
+
+			// Handle making another session the tracker for a limited time, after which the role is released
+			cmd if cmd.starts_with("grant-tracker") => {
+				let parts: Vec<&str> = text.split_whitespace().collect();
+				match parts[..] {
+					_ if !self.admin => "error: not authorized".to_owned(),
+					[_, public_id, duration] => match crate::parse_duration(duration) {
+						Ok(duration) => {
+							self.server
+								.call_with(|respond_to| Message::GrantTracker {
+									public_id: public_id.to_owned(),
+									duration,
+									respond_to,
+								})
+								.await
+						}
+						Err(err) => format!("error: {}", err),
+					},
+					_ => "error: usage: grant-tracker <id> <duration>".to_owned(),
+				}
+			}
+
+			// Handle registering as a backup that takes over the tracker role if it's lost
+			"claim backup" => {
+				if self.may_claim() {
+					self.server
+						.call_with(|respond_to| Message::ClaimBackup {
+							id: self.id,
+							respond_to,
+						})
+						.await
+				} else {
+					"error: not permitted to claim".to_owned()
+				}
+			}
+
+			// Handle claiming the tracker role
+			cmd if cmd.starts_with("claim") => match parse_priority(cmd.split_whitespace().nth(1)) {
+				_ if !self.may_claim() => "error: not permitted to claim".to_owned(),
+				Ok(priority) => {
+					self.server
+						.call_with(|respond_to| Message::Claim {
+							id: self.id,
+							priority,
+							respond_to,
+						})
+						.await
+				}
+				Err(_) => "error: invalid priority".to_owned(),
+			},
+
+			// Handle dropping admin access and permitted keys, releasing the tracker role along with them
+			"logout" => {
+				if self.admin || !self.permitted_keys.is_empty() {
+					self.admin = false;
+					self.permitted_keys.clear();
+					self.logs.stop(&self.id);
+					tracing::info!("Session {} logged out", self.id);
+					self.server
+						.call_with(|respond_to| Message::Logout {
+							id: self.id,
+							respond_to,
+						})
+						.await
+				} else {
+					"error: not authenticated".to_owned()
+				}
+			}
+
+			// Handle tailing the logs
+			cmd if cmd.starts_with("logs") => {
+				let parts: Vec<&str> = cmd.split_whitespace().collect();
+				match parts.get(1) {
+					_ if !self.admin => "error: not authorized".to_owned(),
+					Some(&"tail") => match parts
+						.get(2)
+						.map_or(Ok(LevelFilter::INFO), |level| LevelFilter::from_str(level))
+					{
+						Ok(level) => {
+							self.logs.tail(self.handle.clone(), level);
+							"ok".to_owned()
+						}
+						Err(_) => "error: unknown log level".to_owned(),
+					},
+					Some(&"stop") => {
+						self.logs.stop(&self.id);
+						"ok".to_owned()
+					}
+					_ => "error: unknown logs command".to_owned(),
+				}
+			}
+
+			// Handle changing the max level of logs that are output
+			cmd if cmd.starts_with("log-level") => {
+				let level = cmd.split_whitespace().nth(1).map(LevelFilter::from_str);
+				match (&self.config.log_level, level) {
+					_ if !self.admin => "error: not authorized".to_owned(),
+					(Some(handle), Some(Ok(level))) => match handle.modify(|filter| *filter = level) {
+						Ok(..) => {
+							tracing::info!("Log level changed to {} by session {}", level, self.id);
+							"ok".to_owned()
+						}
+						Err(err) => format!("error: unable to change log level: {}", err),
+					},
+					(None, _) => "error: log level can't be changed".to_owned(),
+					(_, Some(Err(_))) => "error: unknown log level".to_owned(),
+					(_, None) => "error: usage: log-level <level>".to_owned(),
+				}
+			}
+
+			// Handle refusing new connections and telling existing sessions to move elsewhere, optionally closing them
+			// after a timeout
+			cmd if cmd.starts_with("drain") => {
+				let timeout = cmd.split_whitespace().nth(1).map(crate::parse_duration).transpose();
+				match timeout {
+					_ if !self.admin => "error: not authorized".to_owned(),
+					Ok(timeout) => {
+						self.server
+							.call_with(|respond_to| Message::Drain { timeout, respond_to })
+							.await
+					}
+					Err(err) => format!("error: {}", err),
+				}
+			}
+
+			// Handle telling all sessions to reconnect to another server, optionally draining this one afterwards (the URL is
+			// taken from the original text to preserve its case)
+			cmd if cmd.starts_with("redirect") => {
+				let parts: Vec<&str> = text.split_whitespace().collect();
+				let drain = match parts[..] {
+					[_, _] => Ok(None),
+					[_, _, drain] if drain.eq_ignore_ascii_case("drain") => Ok(Some(None)),
+					[_, _, drain, timeout] if drain.eq_ignore_ascii_case("drain") => {
+						crate::parse_duration(timeout).map(|timeout| Some(Some(timeout)))
+					}
+					_ => Err("usage: redirect <url> [drain [timeout]]".to_owned()),
+				};
+				match drain {
+					_ if !self.admin => "error: not authorized".to_owned(),
+					Ok(_) if !is_websocket_url(parts[1]) => "error: invalid websocket URL".to_owned(),
+					Ok(drain) => {
+						let response = self
+							.server
+							.call_with(|respond_to| Message::Redirect {
+								url: parts[1].to_owned(),
+								respond_to,
+							})
+							.await;
+						match drain {
+							Some(timeout) => {
+								self.server
+									.call_with(|respond_to| Message::Drain { timeout, respond_to })
+									.await
+							}
+							None => response,
+						}
+					}
+					Err(err) => format!("error: {}", err),
+				}
+			}
+
+			// Handle sending an operator message to all sessions (the text is taken from the original text to preserve
+			// its case)
+			cmd if cmd.starts_with("broadcast") => {
+				let message = text.trim().get("broadcast".len()..).and_then(sanitize_message);
+				match message {
+					_ if !self.admin => "error: not authorized".to_owned(),
+					Some(message) => {
+						self.server
+							.call_with(|respond_to| Message::Broadcast {
+								text: message,
+								respond_to,
+							})
+							.await
+					}
+					None => "error: empty message".to_owned(),
+				}
+			}
+
+			// Handle writing a snapshot of the current state to a file in the data directory (the path is taken from the
+			// original text to preserve its case)
+			cmd if cmd.starts_with("snapshot-now") => {
+				let path = text.split_whitespace().nth(1);
+				match (&self.config.data_dir, path) {
+					_ if !self.admin => "error: not authorized".to_owned(),
+					(None, _) => "error: no data directory".to_owned(),
+					(Some(data_dir), Some(path)) if is_contained_path(Path::new(path)) => {
+						self.server
+							.call_with(|respond_to| Message::Snapshot {
+								path: data_dir.join(path),
+								respond_to,
+							})
+							.await
+					}
+					(Some(_), Some(_)) => "error: snapshot path must be relative to the data directory".to_owned(),
+					(Some(_), None) => "error: usage: snapshot-now <path>".to_owned(),
+				}
+			}
+
+			// Handle replaying a recording of value changes from a file in the data directory (the path is taken from the
+			// original text to preserve its case)
+			cmd if cmd.starts_with("replay") => {
+				let parts: Vec<&str> = text.split_whitespace().skip(1).collect();
+				let speed = match parts.get(1) {
+					Some(speed) => speed
+						.parse::<f64>()
+						.ok()
+						.filter(|speed| speed.is_finite() && *speed > 0.0),
+					None => Some(1.0),
+				};
+				match (&self.config.data_dir, parts.first(), speed) {
+					_ if !self.admin => "error: not authorized".to_owned(),
+					(None, _, _) => "error: no data directory".to_owned(),
+					(Some(_), Some(_), None) => "error: invalid speed".to_owned(),
+					(Some(data_dir), Some(path), Some(speed)) if is_contained_path(Path::new(path)) => {
+						match tokio::fs::read_to_string(data_dir.join(path)).await {
+							Ok(contents) => {
+								let records = parse_replay_file(&contents);
+								if records.is_empty() {
+									"error: no values to replay".to_owned()
+								} else {
+									tracing::info!("Replaying {} values from {} at {}x", records.len(), path, speed);
+									let response = format!("ok: replaying {} values", records.len());
+									tokio::spawn(replay(self.server.clone(), records, speed));
+									response
+								}
+							}
+							Err(err) => format!("error: unable to read replay file: {}", err),
+						}
+					}
+					(Some(_), Some(_), _) => "error: replay path must be relative to the data directory".to_owned(),
+					(Some(_), None, _) => "error: usage: replay <path> [speed]".to_owned(),
+				}
+			}
+
+			// Handle pinning a key to a value that the tracker can't change until it's unpinned
+			cmd if cmd.starts_with("pin ") || cmd.starts_with("unpin") => {
+				let parts: Vec<&str> = cmd.split_whitespace().collect();
+				let pin = match parts[..] {
+					["pin", key, val] if value_spec(key).is_some() => match val.parse::<Value>() {
+						Ok(val) => Ok((key, Some(val))),
+						Err(_) => Err(format!("error: unknown input for {} value", key)),
+					},
+					["unpin", key] if value_spec(key).is_some() => Ok((key, None)),
+					["pin", _, _] | ["unpin", _] => Err("error: unknown value key".to_owned()),
+					_ => Err("error: usage: pin <key> <value> / unpin <key>".to_owned()),
+				};
+				match pin {
+					_ if !self.admin => "error: not authorized".to_owned(),
+					Ok((key, val)) => {
+						self.server
+							.call_with(|respond_to| Message::Pin {
+								key: key.to_owned(),
+								val,
+								respond_to,
+							})
+							.await
+					}
+					Err(err) => err,
+				}
+			}
+
+			// Handle disabling a key so it can't be set and isn't broadcast (e.g. while its sensor is malfunctioning)
+			cmd if cmd.starts_with("disable-key") || cmd.starts_with("enable-key") => {
+				let parts: Vec<&str> = cmd.split_whitespace().collect();
+				match parts[..] {
+					_ if !self.admin => "error: not authorized".to_owned(),
+					[verb, key] if value_spec(key).is_some() => {
+						self.server
+							.call_with(|respond_to| Message::SetKeyEnabled {
+								key: key.to_owned(),
+								enabled: verb == "enable-key",
+								respond_to,
+							})
+							.await
+					}
+					[_, _] => "error: unknown value key".to_owned(),
+					_ => "error: usage: disable-key <key> / enable-key <key>".to_owned(),
+				}
+			}
+
+			// Handle subscribing to connect/disconnect events
+			"events on" | "events off" => {
+				if self.admin {
+					self.server
+						.call_with(|respond_to| Message::Events {
+							id: self.id,
+							enabled: cmd == "events on",
+							respond_to,
+						})
+						.await
+				} else {
+					"error: not authorized".to_owned()
+				}
+			}
+
+			// Handle muting/unmuting broadcasts of a key to this session
+			cmd if cmd.starts_with("mute") || cmd.starts_with("unmute") => {
+				let parts: Vec<&str> = cmd.split_whitespace().collect();
+				match parts[..] {
+					[verb, key] => {
+						self.server
+							.call_with(|respond_to| Message::Mute {
+								id: self.id,
+								key: key.to_owned(),
+								muted: verb == "mute",
+								respond_to,
+							})
+							.await
+					}
+					_ => "error: usage: mute <key> / unmute <key>".to_owned(),
+				}
+			}
+
+			// Handle identifying the device the client is running on (the ID is taken from the original text to preserve its
+			// case)
+			cmd if cmd.starts_with("device-id") => match text.split_whitespace().nth(1) {
+				Some(device_id) => {
+					self.server
+						.call_with(|respond_to| Message::SetDeviceId {
+							id: self.id,
+							device_id: device_id.to_owned(),
+							replace: self.may_claim(),
+							respond_to,
+						})
+						.await
+				}
+				None => "error: usage: device-id <id>".to_owned(),
+			},
+
+			// Handle getting how many more commands this session's origin can send in the current window
+			"my-limit" => match self.origin_remaining() {
+				Some((limit, remaining)) => format!("my-limit: limit={}/s remaining={}", limit, remaining),
+				None => "my-limit: none".to_owned(),
+			},
+
+			// Handle summarizing all of this session's options and its role
+			"session-state" => {
+				let server_state = self
+					.server
+					.call_with(|respond_to| Message::GetSessionState {
+						id: self.id,
+						respond_to,
+					})
+					.await;
+				format!(
+					"session-state: id={} admin={} readonly={} echo={} quiet={} permitted={} {}",
+					self.public_id,
+					yes_no(self.admin),
+					yes_no(self.readonly),
+					yes_no(self.echo),
+					yes_no(self.quiet),
+					format_list(&self.permitted_keys),
+					server_state
+				)
+			}
+
+			// Handle listing the keys this session is notified of and the ones it has muted
+			"subscriptions" => {
+				self.server
+					.call_with(|respond_to| Message::GetSubscriptions {
+						id: self.id,
+						respond_to,
+					})
+					.await
+			}
+
+			// Handle limiting how often this session is notified of changes to a key (0 removes the limit)
+			cmd if cmd.starts_with("rate") => {
+				let parts: Vec<&str> = cmd.split_whitespace().collect();
+				match parts[..] {
+					[_, key, hz] => match parse_rate(hz) {
+						Ok(interval) => {
+							self.server
+								.call_with(|respond_to| Message::Rate {
+									id: self.id,
+									key: key.to_owned(),
+									interval,
+									respond_to,
+								})
+								.await
+						}
+						Err(_) => "error: invalid rate".to_owned(),
+					},
+					_ => "error: usage: rate <key> <hz>".to_owned(),
+				}
+			}
+
+			// Handle toggling command echoing
+			"echo on" => {
+				self.echo = true;
+				"ok".to_owned()
+			}
+			"echo off" => {
+				self.echo = false;
+				"ok".to_owned()
+			}
+
+			// Handle toggling acknowledgement of successful sets
+			"quiet on" => {
+				self.quiet = true;
+				"ok".to_owned()
+			}
+			"quiet off" => {
+				self.quiet = false;
+				"ok".to_owned()
+			}
+
+			// Handle summarizing the configuration
+			"config" => {
+				if self.admin {
+					format!("config: {}", self.config.summary())
+				} else {
+					"error: not authorized".to_owned()
+				}
+			}
+
+			// Handle reporting the errors this session has run into
+			"my-errors" => format!(
+				"my-errors: parse={} rejected-sets={} rate-limited={} other={}",
+				self.errors.parse, self.errors.rejected_sets, self.errors.rate_limited, self.errors.other
+			),
+
+			// List the values that this session is permitted to set
+			"my-keys" => {
+				let keys: Vec<&str> = VALUE_SPECS
+					.iter()
+					.map(|spec| spec.key)
+					.filter(|key| !self.readonly && self.may_set(key))
+					.collect();
+				if keys.is_empty() {
+					"my-keys: none".to_owned()
+				} else {
+					format!("my-keys: {}", keys.join(" "))
+				}
+			}
+
+			// List the values that can be set, along with how to display them
+			"capabilities" => {
+				let specs: Vec<String> = VALUE_SPECS
+					.iter()
+					.map(|spec| match spec.label {
+						Some(label) => format!("{} unit={:?} label={:?}", spec.key, spec.unit, label),
+						None => format!("{} unit={:?}", spec.key, spec.unit),
+					})
+					.collect();
+				format!("capabilities: {}", specs.join(", "))
+			}
+
+			"server-version" => match option_env!("HEARTSOCK_GIT_HASH") {
+				Some(hash) => format!("server-version: {} ({})", env!("CARGO_PKG_VERSION"), hash),
+				None => format!("server-version: {}", env!("CARGO_PKG_VERSION")),
+			},
+
+			// Handle resyncing all values, along with a version that lets clients detect missed changes
+			"sync" => self.server.call_with(|respond_to| Message::Sync { respond_to }).await,
+
+			// Handle getting everything about every key in one go
+			"state" => {
+				self.server
+					.call_with(|respond_to| Message::GetState { respond_to })
+					.await
+			}
+
+			"tracker-uptime" => {
+				self.server
+					.call_with(|respond_to| Message::TrackerUptime { respond_to })
+					.await
+			}
+
+			"ping" => self.server.call_with(|respond_to| Message::Ping { respond_to }).await,
+			_ => "error: unknown input".to_owned(),
+		};
+
+		// Record set commands in the audit log, whether they were accepted or not
+		if self.config.audit_log.is_some() && (cmd.starts_with("set") || cmd.starts_with("cas")) {
+			self.server.call(Message::Audit {
+				id: self.id,
+				command: text.trim().to_owned(),
+				response: response.clone(),
+			});
+		}
+
+		// Keep count of the errors the session has run into, and of the server's rejected sets (any command can be rate
+		// limited, but only sets count towards the server's rejections)
+		if response.starts_with("error:") {
+			let is_set = cmd.starts_with("set") || cmd.starts_with("cas");
+			if is_parse_error(&response) {
+				self.errors.parse += 1;
+			} else if response == RATE_LIMITED {
+				self.errors.rate_limited += 1;
+			} else if is_set {
+				self.errors.rejected_sets += 1;
+			} else {
+				self.errors.other += 1;
+			}
+
+			if is_set && !is_parse_error(&response) {
+				self.server.call(Message::Rejected {
+					reason: rejection_reason(&response),
+				});
+			}
+		}
+
+		// Skip acknowledging successful sets in quiet mode (errors are still sent)
+		if self.quiet && cmd.starts_with("set") && response == "ok" {
+			return Ok(());
+		}
+
+		self.respond(&text, response);
+		Ok(())
+	}
+
+	// Binary data received from client
+	async fn on_binary(&mut self, _bytes: Vec<u8>) -> Result<(), ezsockets::Error> {
+		tracing::debug!("Received binary data (unsupported) from session {}", self.id);
+		self.errors.parse += 1;
+		self.handle.text("error: binary data unsupported".to_owned());
+		Ok(())
+	}
+
+	// Unused
+	async fn on_call(&mut self, _call: Self::Call) -> Result<(), ezsockets::Error> {
+		Ok(())
+	}
+}
+
+/// Sends a response to a message (the session may have disconnected while waiting for it, in which case there's nobody
+/// to send it to)
+fn respond(respond_to: Responder, response: String) {
+	let _ = respond_to.send(response);
+}
+
+/// Normalizes the case of a command for matching, leaving its arguments as they are if they're case-sensitive (only
+/// ASCII letters are lowercased, so non-ASCII keys and arguments always have to match exactly)
+fn normalize_command(text: &str, case_sensitive_args: bool) -> String {
+	match text.split_once(char::is_whitespace) {
+		Some((name, args)) if case_sensitive_args => format!("{} {}", name.to_ascii_lowercase(), args),
+		_ => text.to_ascii_lowercase(),
+	}
+}
+
+/// Checks whether text is a JSON handshake object (one with "handshake": true)
+fn is_handshake(text: &str) -> bool {
+	text.trim_start().starts_with('{')
+		&& serde_json::from_str::<serde_json::Value>(text)
+			.is_ok_and(|val| val.get("handshake").and_then(serde_json::Value::as_bool) == Some(true))
+}
+
+/// Checks whether text only has printable ASCII characters and spaces
+fn is_printable_ascii(text: &str) -> bool {
+	text.chars().all(|c| c.is_ascii_graphic() || c == ' ')
+}
+
+/// Checks whether an error response means the command couldn't be understood
+fn is_parse_error(response: &str) -> bool {
+	const PREFIXES: [&str; 13] = [
+		"error: unknown input",
+		"error: usage:",
+		"error: unknown value key",
+		"error: unknown option",
+		"error: unknown duration unit",
+		"error: invalid duration",
+		"error: invalid rate",
+		"error: invalid priority",
+		"error: unknown log level",
+		"error: unknown logs command",
+		"error: empty message",
+		"error: cannot convert",
+		"error: invalid command encoding",
+	];
+	PREFIXES.iter().any(|prefix| response.starts_with(prefix))
+}
+
+/// Checks whether a URL is a WebSocket URL that clients can be redirected to
+fn is_websocket_url(url: &str) -> bool {
+	match url.parse::<tungstenite::http::Uri>() {
+		Ok(uri) => matches!(uri.scheme_str(), Some("ws" | "wss")) && uri.host().is_some_and(|host| !host.is_empty()),
+		Err(_) => false,
+	}
+}
+
+/// Turns the error response to a rejected set into a short reason to count it under (e.g. "error: cas mismatch
+/// (current=60)" becomes "cas-mismatch")
+fn rejection_reason(response: &str) -> String {
+	let reason = response.strip_prefix("error: ").unwrap_or(response);
+	let reason = reason.split(" (").next().unwrap_or(reason);
+	reason
+		.chars()
+		.filter(|c| c.is_ascii_alphanumeric() || *c == ' ' || *c == '-')
+		.collect::<String>()
+		.split_whitespace()
+		.collect::<Vec<_>>()
+		.join("-")
+}
+
+/// Cleans up an operator message by replacing control characters (including line breaks) with spaces, trimming it, and
+/// cutting it off at the max length, returning nothing if there's nothing left
+fn sanitize_message(text: &str) -> Option<String> {
+	let text: String = text
+		.chars()
+		.map(|c| if c.is_control() { ' ' } else { c })
+		.collect::<String>()
+		.trim()
+		.chars()
+		.take(MAX_MESSAGE_LEN)
+		.collect();
+	(!text.is_empty()).then_some(text)
+}
+
+/// Gets the number of milliseconds since the Unix epoch for a time
+fn unix_millis(time: SystemTime) -> u64 {
+	time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// Parses the optional `pri=<priority>`, `ttl=<duration>`, and `ts=<timestamp>` arguments of a set command
+fn parse_set_options(args: &[&str]) -> Result<SetOptions, String> {
+	let mut options = SetOptions::default();
+	for arg in args {
+		match arg.split_once('=') {
+			Some(("pri", _)) => options.priority = parse_priority(Some(arg)).map_err(|_| "invalid priority")?,
+			Some(("ttl", ttl)) => options.ttl = Some(crate::parse_duration(ttl)?),
+			Some(("ts", ts)) => options.timestamp = Some(ts.parse().map_err(|_| "invalid timestamp")?),
+			_ => return Err(format!("unknown option \"{}\"", arg)),
+		}
+	}
+	Ok(options)
+}
+
+/// Checks whether a path is relative and stays within the directory it's relative to (no root, prefix, or parent
+/// components)
+fn is_contained_path(path: &Path) -> bool {
+	path.components()
+		.all(|component| matches!(component, Component::Normal(..) | Component::CurDir))
+		&& path
+			.components()
+			.any(|component| matches!(component, Component::Normal(..)))
+}
+
+/// Parses a rate in Hz into the interval between updates, or nothing for a rate of 0
+fn parse_rate(hz: &str) -> Result<Option<Duration>, ()> {
+	match hz.parse::<f64>() {
+		Ok(0.0) => Ok(None),
+		Ok(hz) => Duration::try_from_secs_f64(1.0 / hz).map(Some).map_err(|_| ()),
+		Err(_) => Err(()),
+	}
+}
+
+/// Formats captured headers for logs and responses, with a leading space (or nothing if there aren't any)
+fn format_headers(headers: &[(String, String)]) -> String {
+	headers
+		.iter()
+		.map(|(name, val)| format!(" {}: {:?}", name, val))
+		.collect()
+}
+
+/// Formats items as a sorted comma-separated list for a response, or "none" if there aren't any
+fn format_list<I>(items: I) -> String
+where
+	I: IntoIterator,
+	I::Item: AsRef<str>,
+{
+	let mut items: Vec<String> = items.into_iter().map(|item| item.as_ref().to_owned()).collect();
+	items.sort_unstable();
+	if items.is_empty() {
+		"none".to_owned()
+	} else {
+		items.join(",")
+	}
+}
+
+/// Formats whether something is enabled as "yes" or "no" for a response
+fn yes_no(enabled: bool) -> &'static str {
+	if enabled {
+		"yes"
+	} else {
+		"no"
+	}
+}
+
+/// Parses an optional `pri=<priority>` command argument
+fn parse_priority(arg: Option<&str>) -> Result<Option<Priority>, ()> {
+	match arg {
+		Some(arg) => {
+			let priority = arg.strip_prefix("pri=").ok_or(())?;
+			priority.parse().map(Some).map_err(|_| ())
+		}
+		None => Ok(None),
+	}
+}
+
+impl HeartsockSession {
+	/// Splits a command's arguments into the key they're for and the rest of them, using the default key (if there
+	/// is one) when the first argument isn't a settable key
+	fn split_key<'a>(&'a self, args: &'a [&'a str]) -> Option<(&'a str, &'a [&'a str])> {
+		match args.first() {
+			Some(key) if value_spec(key).is_some() => Some((key, &args[1..])),
+			_ => self.config.default_key.as_deref().map(|key| (key, args)),
+		}
+	}
+
+	/// Counts a command against the session's origin (if it has one and origins are limited), returning whether the
+	/// origin has gone over its limit for the current window
+	fn origin_limited(&self) -> bool {
+		let (limit, origin) = match (self.config.origin_rate_limit, &self.origin) {
+			(Some(limit), Some(origin)) => (limit, origin),
+			_ => return false,
+		};
+
+		let now = Instant::now();
+		let mut windows = self.origin_windows.lock().unwrap_or_else(PoisonError::into_inner);
+		let window = windows
+			.entry(origin.clone())
+			.or_insert(OriginWindow { started: now, count: 0 });
+		if now.duration_since(window.started) >= ORIGIN_WINDOW {
+			*window = OriginWindow { started: now, count: 0 };
+		}
+		window.count += 1;
+		window.count > limit
+	}
+
+	/// Gets the session's origin's limit and how many more commands it can send in the current window (if it has an
+	/// origin and origins are limited)
+	fn origin_remaining(&self) -> Option<(u32, u32)> {
+		let limit = self.config.origin_rate_limit?;
+		let origin = self.origin.as_ref()?;
+		let windows = self.origin_windows.lock().unwrap_or_else(PoisonError::into_inner);
+		let used = windows
+			.get(origin)
+			.filter(|window| window.started.elapsed() < ORIGIN_WINDOW)
+			.map_or(0, |window| window.count);
+		Some((limit, limit.saturating_sub(used)))
+	}
+
+	/// Checks whether the session is permitted to set a key (always the case if there aren't any access control tokens)
+	fn may_set(&self, key: &str) -> bool {
+		self.config.acl.is_empty() || self.admin || self.permitted_keys.contains(key)
+	}
+
+	/// Checks whether the session is permitted to claim the tracker or backup role, which it needs to be permitted to
+	/// set at least one key for (always the case if there aren't any access control tokens)
+	fn may_claim(&self) -> bool {
+		self.config.acl.is_empty() || self.admin || !self.permitted_keys.is_empty()
+	}
+
+	/// Applies the options in a JSON handshake object, stopping at the first one that fails. Supported options are
+	/// "echo" and "quiet" (booleans), "device_id" (a string), "mute" (an array of keys), "role" ("tracker", "backup", or
+	/// "observer"), and "priority" (for the tracker role).
+	async fn handshake(&mut self, text: &str) -> String {
+		let handshake: serde_json::Map<String, serde_json::Value> = match serde_json::from_str(text) {
+			Ok(handshake) => handshake,
+			Err(_) => return "error: invalid handshake".to_owned(),
+		};
+		let invalid = |option: &str| format!("error: invalid handshake option \"{}\"", option);
+
+		for (option, field) in [("echo", &mut self.echo), ("quiet", &mut self.quiet)] {
+			match handshake.get(option).map(serde_json::Value::as_bool) {
+				Some(Some(enabled)) => *field = enabled,
+				Some(None) => return invalid(option),
+				None => {}
+			}
+		}
+
+		match handshake.get("device_id").map(serde_json::Value::as_str) {
+			Some(Some(_)) if self.readonly => return "error: read-only connection".to_owned(),
+			Some(Some(device_id)) => {
+				let response = self
+					.server
+					.call_with(|respond_to| Message::SetDeviceId {
+						id: self.id,
+						device_id: device_id.to_owned(),
+						replace: self.may_claim(),
+						respond_to,
+					})
+					.await;
+				if response.starts_with("error") {
+					return response;
+				}
+			}
+			Some(None) => return invalid("device_id"),
+			None => {}
+		}
+
+		if let Some(keys) = handshake.get("mute") {
+			let keys = match keys.as_array() {
+				Some(keys) => keys,
+				None => return invalid("mute"),
+			};
+			for key in keys {
+				let key = match key.as_str() {
+					Some(key) => key.to_ascii_lowercase(),
+					None => return invalid("mute"),
+				};
+				let response = self
+					.server
+					.call_with(|respond_to| Message::Mute {
+						id: self.id,
+						key,
+						muted: true,
+						respond_to,
+					})
+					.await;
+				if response.starts_with("error") {
+					return response;
+				}
+			}
+		}
+
+		let priority = match handshake.get("priority") {
+			Some(priority) => match priority.as_u64().and_then(|priority| Priority::try_from(priority).ok()) {
+				Some(priority) => Some(priority),
+				None => return invalid("priority"),
+			},
+			None => None,
+		};
+		match handshake.get("role").map(serde_json::Value::as_str) {
+			Some(Some("tracker" | "backup")) if self.readonly => "error: read-only connection".to_owned(),
+			Some(Some("tracker" | "backup")) if !self.may_claim() => "error: not permitted to claim".to_owned(),
+			Some(Some("tracker")) => {
+				self.server
+					.call_with(|respond_to| Message::Claim {
+						id: self.id,
+						priority,
+						respond_to,
+					})
+					.await
+			}
+			Some(Some("backup")) => {
+				self.server
+					.call_with(|respond_to| Message::ClaimBackup {
+						id: self.id,
+						respond_to,
+					})
+					.await
+			}
+			Some(Some("observer")) | None => "ok".to_owned(),
+			Some(_) => invalid("role"),
+		}
+	}
+
+	/// Sends a response to a command, prefixed with the command if echoing is enabled
+	fn respond(&self, cmd: &str, response: String) {
+		if self.echo {
+			self.handle.text(format!("[{}] {}", cmd.trim(), response));
+		} else {
+			self.handle.text(response);
+		}
+	}
+}
+
+/// Create and run a Heartsock websocket server, optionally with an additional listener for read-only sessions
+pub async fn run<A>(
+	address: A,
+	readonly_address: Option<A>,
+	config: ServerConfig,
+	logs: LogForwarder,
+) -> Result<(), ezsockets::Error>
+where
+	A: ToSocketAddrs + Display,
+{
+	tracing::info!("WebSocket server starting on {}", address);
+	let config = Arc::new(config);
+	let draining = Arc::new(AtomicBool::new(false));
+
+	let jsonl_out = config
+		.stdout_jsonl
+		.then(|| Box::new(io::stdout()) as Box<dyn Write + Send + Sync>);
+	let server = create_server(config.clone(), logs, draining.clone(), jsonl_out)?;
+
+	let listener = listen(server.clone(), address, config.clone(), draining.clone(), false);
+	let listeners = async {
+		match readonly_address {
+			Some(readonly_address) => {
+				tracing::info!("Read-only WebSocket server starting on {}", readonly_address);
+				let readonly_listener = listen(server.clone(), readonly_address, config.clone(), draining, true);
+				tokio::try_join!(listener, readonly_listener).map(|_| ())
+			}
+			None => listener.await,
+		}
+	};
+
+	// Write anything that's still buffered and wait for all sessions to close before exiting on Ctrl+C or SIGTERM,
+	// giving up on that if it takes too long so shutdown can't hang
+	tokio::select! {
+		result = listeners => result,
+		_ = shutdown_signal() => {
+			shut_down(&server, config.shutdown_timeout).await;
+			Ok(())
+		}
+	}
+}
+
+/// Has the server write anything that's still buffered and waits for all sessions to close, giving up after a timeout so
+/// stuck sessions can't hang shutdown. Returns whether the server finished before the timeout.
+async fn shut_down(server: &Server<HeartsockServer>, timeout: Duration) -> bool {
+	let shutdown = server.call_with(|respond_to| Message::Shutdown { respond_to });
+	let finished = tokio::time::timeout(timeout, shutdown).await.is_ok();
+	if !finished {
+		tracing::warn!("Shutdown took longer than {:?} - exiting anyway", timeout);
+	}
+	finished
+}
+
+/// Creates the server actor with its initial state and starts its timers, without listening for connections
+fn create_server(
+	config: Arc<ServerConfig>,
+	logs: LogForwarder,
+	draining: Arc<AtomicBool>,
+	jsonl_out: Option<Box<dyn Write + Send + Sync>>,
+) -> Result<Server<HeartsockServer>, ezsockets::Error> {
+	// Seed the values from a previous run if desired
+	let mut values = HashMap::from([
+		(KEY_TRACKER.to_owned(), 0),
+		(KEY_BPM.to_owned(), 0),
+		(KEY_BATTERY.to_owned(), 0),
+	]);
+	let restored = match &config.data_dir {
+		Some(data_dir) if config.restore_from_data_dir => restore_values(data_dir, config.data_format),
+		_ => HashMap::new(),
+	};
+	let set_keys = restored.keys().cloned().collect();
+	values.extend(restored);
+
+	// Open the audit log for appending (if enabled)
+	let audit_log = match &config.audit_log {
+		Some(path) => {
+			let file = OpenOptions::new().create(true).append(true).open(path)?;
+			tracing::info!("Writing audit log to {}", path.display());
+			Some(BufWriter::new(file))
+		}
+		None => None,
+	};
+
+	let (server, _) = ezsockets::Server::create(|handle| HeartsockServer {
+		sessions: HashMap::new(),
+		audit_log,
+		jsonl_out,
+		public_ids: HashMap::new(),
+		addresses: HashMap::new(),
+		sinks: HashMap::new(),
+		draining: draining.clone(),
+		shutdown: None,
+		handle,
+		config: config.clone(),
+		logs,
+		latest_id: 0,
+		tracker_id: None,
+		tracker_since: None,
+		tracker_ip: None,
+		tracker_grant: None,
+		latest_grant_id: 0,
+		backups: Vec::new(),
+		values,
+		pending_broadcasts: HashSet::new(),
+		set_keys,
+		smoothed: HashMap::new(),
+		rejections: HashMap::new(),
+		peaks: HashMap::new(),
+		priorities: HashMap::new(),
+		event_subscribers: HashSet::new(),
+		muted_keys: HashMap::new(),
+		rate_limits: HashMap::new(),
+		pending_reverts: HashMap::new(),
+		pinned_keys: HashSet::new(),
+		disabled_keys: HashSet::new(),
+		updated_at: HashMap::new(),
+		set_at: HashMap::new(),
+		state_version: 0,
+		unwritten_keys: HashSet::new(),
+		timestamps: HashMap::new(),
+		has_observers: false,
+		origin_windows: OriginWindows::default(),
+		device_ids: HashMap::new(),
+		sources: HashMap::new(),
+		sequences: HashMap::new(),
+		latest_revert_id: 0,
+	});
+
+	// Periodically broadcast changes to keys that have a broadcast interval (keys with the same interval share a timer,
+	// so their changes can be combined)
+	let mut broadcast_timers: HashMap<Duration, Vec<String>> = HashMap::new();
+	for (key, interval) in &config.broadcast_intervals {
+		broadcast_timers.entry(*interval).or_default().push(key.clone());
+	}
+	for (interval, mut keys) in broadcast_timers {
+		keys.sort();
+		spawn_timer(&server, interval, move || Message::FlushBroadcast {
+			keys: keys.clone(),
+		});
+	}
+
+	// Apply values from the watched file whenever it changes
+	if let Some(path) = &config.watch_file {
+		tracing::info!("Watching {} for values", path.display());
+		tokio::spawn(watch_file(server.clone(), path.clone()));
+	}
+
+	// Periodically forget origins whose command windows have expired, so they don't pile up
+	if config.origin_rate_limit.is_some() {
+		spawn_timer(&server, ORIGIN_PRUNE_INTERVAL, || Message::PruneOriginWindows);
+	}
+
+	// Periodically flush the audit log
+	if config.audit_log.is_some() {
+		spawn_timer(&server, AUDIT_FLUSH_INTERVAL, || Message::FlushAuditLog);
+	}
+
+	// Periodically let sessions know the server is still alive
+	if let Some(interval) = config.keepalive_interval {
+		spawn_timer(&server, interval, || Message::Keepalive);
+	}
+
+	// Periodically rewrite the value files
+	if let Some(interval) = config.data_dir.as_ref().and(config.data_flush_interval) {
+		spawn_timer(&server, interval, || Message::FlushData);
+	}
+
+	// Periodically write values that have changed, if writes are batched
+	if let Some(interval) = config.data_dir.as_ref().and(config.data_write_interval) {
+		spawn_timer(&server, interval, || Message::WriteData);
+	}
+
+	Ok(server)
+}
+
+/// Waits for Ctrl+C or (on Unix) SIGTERM, never completing if neither can be listened for
+async fn shutdown_signal() {
+	let ctrl_c = async {
+		if let Err(err) = tokio::signal::ctrl_c().await {
+			tracing::error!("Unable to listen for Ctrl+C: {}", err);
+			std::future::pending::<()>().await;
+		}
+	};
+
+	#[cfg(unix)]
+	let terminate = async {
+		match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+			Ok(mut signal) => {
+				signal.recv().await;
+			}
+			Err(err) => {
+				tracing::error!("Unable to listen for SIGTERM: {}", err);
+				std::future::pending::<()>().await;
+			}
+		}
+	};
+	#[cfg(not(unix))]
+	let terminate = std::future::pending::<()>();
+
+	tokio::select! {
+		_ = ctrl_c => tracing::info!("Received Ctrl+C"),
+		_ = terminate => tracing::info!("Received SIGTERM"),
+	}
+}
+
+/// Reads the values of all settable keys from the files in a data directory, skipping any that are missing or invalid
+fn restore_values(data_dir: &Path, format: DataFormat) -> HashMap<String, Value> {
+	// Prefer state.json when it's the only file being written, and the individual value files otherwise
+	let state = (format == DataFormat::Json).then(|| read_state_file(&data_dir.join("state.json")));
+
+	let mut values = HashMap::new();
+	for spec in &VALUE_SPECS {
+		let val = match &state {
+			Some(Ok(state)) => state_file_val(state, spec.key),
+			Some(Err(err)) => Err(err.clone()),
+			None => read_val_file(&data_dir.join(format!("{}.txt", spec.key))),
+		};
+
+		match val {
+			Ok(Some(val)) => {
+				tracing::info!("Restored \"{}\" value \"{}\" from the data directory", spec.key, val);
+				values.insert(spec.key.to_owned(), val);
+			}
+			Ok(None) => tracing::debug!("No \"{}\" value to restore from the data directory", spec.key),
+			Err(err) => tracing::warn!("Unable to restore \"{}\" value: {}", spec.key, err),
+		}
+	}
+	values
+}
+
+/// Reads a value file, returning nothing if it doesn't exist
+fn read_val_file(path: &Path) -> Result<Option<Value>, String> {
+	match fs::read_to_string(path) {
+		Ok(val) => val
+			.trim()
+			.parse()
+			.map(Some)
+			.map_err(|_| format!("invalid value in {}", path.display())),
+		Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+		Err(err) => Err(format!("unable to read {}: {}", path.display(), err)),
+	}
+}
+
+/// Reads and parses a state file, returning null if it doesn't exist
+fn read_state_file(path: &Path) -> Result<serde_json::Value, String> {
+	match fs::read_to_string(path) {
+		Ok(state) => serde_json::from_str(&state).map_err(|err| format!("invalid JSON in {}: {}", path.display(), err)),
+		Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(serde_json::Value::Null),
+		Err(err) => Err(format!("unable to read {}: {}", path.display(), err)),
+	}
+}
+
+/// Gets a key's value from a parsed state file, returning nothing if it isn't there
+fn state_file_val(state: &serde_json::Value, key: &str) -> Result<Option<Value>, String> {
+	match &state["values"][key] {
+		serde_json::Value::Null => Ok(None),
+		val => val
+			.as_u64()
+			.and_then(|val| Value::try_from(val).ok())
+			.map(Some)
+			.ok_or_else(|| format!("invalid value {} in state file", val)),
+	}
+}
+
+/// Accepts connections on an address and hands them off to the server as sessions
+async fn listen<A>(
+	server: Server<HeartsockServer>,
+	address: A,
+	config: Arc<ServerConfig>,
+	draining: Arc<AtomicBool>,
+	readonly: bool,
+) -> Result<(), ezsockets::Error>
+where
+	A: ToSocketAddrs,
+{
+	let listener = TcpListener::bind(address).await?;
+	loop {
+		let (stream, address) = match listener.accept().await {
+			Ok(conn) => conn,
+			Err(err) => {
+				tracing::error!("Failed to accept TCP connection: {}", err);
+				continue;
+			}
+		};
+		accept(&server, stream, address, &config, &draining, readonly).await;
+	}
+}
+
+/// Performs the WebSocket handshake for a connection and hands it off to the server as a session, capturing the
+/// configured headers from the upgrade request and refusing it while draining
+async fn accept<S>(
+	server: &Server<HeartsockServer>,
+	stream: S,
+	address: SocketAddr,
+	config: &ServerConfig,
+	draining: &AtomicBool,
+	readonly: bool,
+) where
+	S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	// The callback's error type is dictated by tungstenite
+	let mut headers = Vec::new();
+	let mut origin = None;
+	#[allow(clippy::result_large_err)]
+	let capture = |request: &Request, response: Response| {
+		if draining.load(Ordering::Relaxed) {
+			let mut response = ErrorResponse::new(Some("Server is draining".to_owned()));
+			*response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+			return Err(response);
+		}
+
+		headers = config
+			.capture_headers
+			.iter()
+			.filter_map(|name| {
+				let val = request.headers().get(name)?.to_str().ok()?;
+				Some((name.clone(), val.to_owned()))
+			})
+			.collect();
+		origin = request
+			.headers()
+			.get("origin")
+			.and_then(|origin| origin.to_str().ok())
+			.map(str::to_owned);
+		Ok(response)
+	};
+	let socket = match tokio_tungstenite::accept_hdr_async(stream, capture).await {
+		Ok(socket) => Socket::new(socket, Default::default()),
+		Err(tungstenite::Error::Http(response)) => {
+			tracing::info!("Refused connection from {} ({})", address, response.status());
+			return;
+		}
+		Err(err) => {
+			tracing::error!("Failed to accept WebSocket connection from {}: {}", address, err);
+			return;
+		}
+	};
+
+	let args = SessionArgs {
+		readonly,
+		headers,
+		origin,
+	};
+	server.accept(socket, address, args).await;
+}
+
+/// Polls a file of key=value lines for changes, sending the server its values whenever it's modified (polling the
+/// modification time also picks up files that are atomically replaced)
+async fn watch_file(server: Server<HeartsockServer>, path: PathBuf) {
+	let mut interval = tokio::time::interval(WATCH_FILE_INTERVAL);
+	let mut last_modified = None;
+	loop {
+		interval.tick().await;
+
+		// The file may be briefly missing while it's being replaced, so just try again next time
+		let modified = match tokio::fs::metadata(&path)
+			.await
+			.and_then(|metadata| metadata.modified())
+		{
+			Ok(modified) => modified,
+			Err(err) => {
+				tracing::debug!("Unable to check watched file {}: {}", path.display(), err);
+				continue;
+			}
+		};
+		if last_modified == Some(modified) {
+			continue;
+		}
+
+		match tokio::fs::read_to_string(&path).await {
+			Ok(contents) => {
+				last_modified = Some(modified);
+				server.call(Message::InjectVals {
+					vals: parse_watched_file(&contents),
+				});
+			}
+			Err(err) => tracing::warn!("Unable to read watched file {}: {}", path.display(), err),
+		}
+	}
+}
+
+/// Parses the key=value lines of a watched file, skipping blank lines, comments (starting with #), and invalid lines
+fn parse_watched_file(contents: &str) -> Vec<(String, Value)> {
+	contents
+		.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.filter_map(|line| {
+			let parsed = line.split_once('=').and_then(|(key, val)| {
+				let key = key.trim().to_ascii_lowercase();
+				let val = val.trim().parse::<Value>().ok()?;
+				value_spec(&key).map(|_| (key, val))
+			});
+			if parsed.is_none() {
+				tracing::warn!("Ignoring invalid line in watched file: {:?}", line);
+			}
+			parsed
+		})
+		.collect()
+}
+
+/// Parses a JSON Lines recording of value changes (as written with --stdout-jsonl) into the time (in milliseconds),
+/// key, and value of each change, skipping lines that aren't valid records
+fn parse_replay_file(contents: &str) -> Vec<(u64, String, Value)> {
+	contents
+		.lines()
+		.filter(|line| !line.trim().is_empty())
+		.filter_map(|line| {
+			let record: serde_json::Value = serde_json::from_str(line).ok()?;
+			let key = record.get("key")?.as_str()?.to_owned();
+			let val = Value::try_from(record.get("value")?.as_u64()?).ok()?;
+			let ts = record.get("ts")?.as_u64()?;
+			value_spec(&key).map(|_| (ts, key, val))
+		})
+		.collect()
+}
+
+/// Sends the server recorded value changes with the same timing between them as when they were recorded, sped up by a
+/// factor
+async fn replay(server: Server<HeartsockServer>, records: Vec<(u64, String, Value)>, speed: f64) {
+	let mut prev_ts = None;
+	for (ts, key, val) in records {
+		if let Some(prev_ts) = prev_ts {
+			// Slow enough speeds make the delay too long to represent
+			let delay = Duration::from_millis(ts.saturating_sub(prev_ts)).as_secs_f64() / speed;
+			match Duration::try_from_secs_f64(delay) {
+				Ok(delay) => tokio::time::sleep(delay).await,
+				Err(_) => {
+					tracing::warn!("Stopping replay, since the delay until the next value is too long");
+					return;
+				}
+			}
+		}
+		prev_ts = Some(ts);
+		server.call(Message::InjectVals { vals: vec![(key, val)] });
+	}
+	tracing::info!("Replay finished");
+}
+
+/// Spawns a task that sends a message to the server on an interval
+fn spawn_timer<F>(server: &Server<HeartsockServer>, interval: Duration, message: F)
+where
+	F: Fn() -> Message + Send + 'static,
+{
+	let server = server.clone();
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(interval);
+		loop {
+			interval.tick().await;
+			server.call(message());
+		}
+	});
+}
+
+/// Harness for testing sessions through in-memory WebSocket connections instead of real ports
+#[cfg(test)]
+pub(crate) mod harness {
+	use super::*;
+	use futures_util::{SinkExt, StreamExt};
+	use tokio::io::DuplexStream;
+	use tokio_tungstenite::{
+		tungstenite::{client::IntoClientRequest, protocol::Role},
+		WebSocketStream,
+	};
+
+	/// How long to wait for a frame from the server before failing a test
+	const RECV_TIMEOUT: Duration = Duration::from_secs(2);
+
+	/// Client connected to a server through an in-memory stream rather than a real port
+	pub(crate) struct TestClient {
+		/// Client end of the WebSocket
+		socket: WebSocketStream<DuplexStream>,
+	}
+
+	impl TestClient {
+		/// Connects a new client to a server, skipping past the values it's sent on connect
+		pub(crate) async fn connect(server: &Server<HeartsockServer>) -> Self {
+			Self::connect_with(server, SessionArgs::default()).await
+		}
+
+		/// Connects a new client to a server with the given session arguments, skipping past the values it's sent on
+		/// connect
+		pub(crate) async fn connect_with(server: &Server<HeartsockServer>, args: SessionArgs) -> Self {
+			let mut client = Self::open(server, args).await;
+			client.sync().await;
+			client
+		}
+
+		/// Connects a new client from an IP to a server, skipping past the values it's sent on connect
+		pub(crate) async fn connect_from(server: &Server<HeartsockServer>, ip: [u8; 4]) -> Self {
+			let mut client = Self::open_from(server, SocketAddr::from((ip, 0)), SessionArgs::default()).await;
+			client.sync().await;
+			client
+		}
+
+		/// Connects a new client to a server without sending anything, so its first message can be a handshake
+		pub(crate) async fn open(server: &Server<HeartsockServer>, args: SessionArgs) -> Self {
+			Self::open_from(server, SocketAddr::from(([127, 0, 0, 1], 0)), args).await
+		}
+
+		/// Connects a new client from an address to a server without sending anything
+		pub(crate) async fn open_from(
+			server: &Server<HeartsockServer>,
+			address: SocketAddr,
+			args: SessionArgs,
+		) -> Self {
+			let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+			let server_socket = WebSocketStream::from_raw_socket(server_stream, Role::Server, None).await;
+			let socket = WebSocketStream::from_raw_socket(client_stream, Role::Client, None).await;
+			server
+				.accept(Socket::new(server_socket, Default::default()), address, args)
+				.await;
+
+			Self { socket }
+		}
+
+		/// Connects a new client to a server with a full WebSocket upgrade request that includes some headers, so the
+		/// server handles it the same as a connection to a listener
+		pub(crate) async fn upgrade(
+			server: &Server<HeartsockServer>,
+			config: &ServerConfig,
+			headers: &[(&'static str, &str)],
+		) -> Self {
+			Self::try_upgrade(server, config, &AtomicBool::new(false), headers)
+				.await
+				.unwrap()
+		}
+
+		/// Attempts to connect a new client to a server with a full WebSocket upgrade request, returning the error if
+		/// the upgrade is refused
+		pub(crate) async fn try_upgrade(
+			server: &Server<HeartsockServer>,
+			config: &ServerConfig,
+			draining: &AtomicBool,
+			headers: &[(&'static str, &str)],
+		) -> Result<Self, tungstenite::Error> {
+			let mut request = "ws://localhost/".into_client_request().unwrap();
+			for (name, val) in headers {
+				request.headers_mut().insert(*name, val.parse().unwrap());
+			}
+
+			let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+			let address = SocketAddr::from(([127, 0, 0, 1], 0));
+			let (handshake, _) = tokio::join!(
+				tokio_tungstenite::client_async(request, client_stream),
+				accept(server, server_stream, address, config, draining, false)
+			);
+			let mut client = Self { socket: handshake?.0 };
+			client.sync().await;
+			Ok(client)
+		}
+
+		/// Skips past everything the server has sent so far by waiting for the response to a ping (which may be echoed)
+		pub(crate) async fn sync(&mut self) {
+			self.send("ping").await;
+			while !self.recv().await.ends_with("pong") {}
+		}
+
+		/// Closes the connection, waiting for the server to acknowledge it
+		pub(crate) async fn close(mut self) {
+			self.socket.close(None).await.unwrap();
+			while self.socket.next().await.is_some() {}
+		}
+
+		/// Sends a line of text to the server
+		pub(crate) async fn send(&mut self, text: &str) {
+			self.send_frame(tungstenite::Message::Text(text.to_owned())).await;
+		}
+
+		/// Sends a raw frame to the server
+		pub(crate) async fn send_frame(&mut self, frame: tungstenite::Message) {
+			self.socket.send(frame).await.unwrap();
+		}
+
+		/// Receives the next frame of any kind from the server, panicking if none arrives in time
+		pub(crate) async fn recv_frame(&mut self) -> tungstenite::Message {
+			self.recv_frame_within(RECV_TIMEOUT).await
+		}
+
+		/// Receives the next frame of any kind from the server, panicking if none arrives within a timeout
+		async fn recv_frame_within(&mut self, timeout: Duration) -> tungstenite::Message {
+			tokio::time::timeout(timeout, self.socket.next())
+				.await
+				.expect("timed out waiting for a frame")
+				.expect("connection closed")
+				.unwrap()
+		}
+
+		/// Receives the next line of text from the server, panicking if none arrives in time
+		pub(crate) async fn recv(&mut self) -> String {
+			self.recv_within(RECV_TIMEOUT).await
+		}
+
+		/// Receives the next line of text from the server, panicking if none arrives within a timeout (for lines sent
+		/// by something slower than the server itself)
+		pub(crate) async fn recv_within(&mut self, timeout: Duration) -> String {
+			loop {
+				if let tungstenite::Message::Text(text) = self.recv_frame_within(timeout).await {
+					return text;
+				}
+			}
+		}
+
+		/// Waits for the server to close the connection, skipping any text it sends first, and returns the close reason
+		pub(crate) async fn recv_close(&mut self) -> String {
+			loop {
+				if let tungstenite::Message::Close(frame) = self.recv_frame().await {
+					return frame.map(|frame| frame.reason.into_owned()).unwrap_or_default();
+				}
+			}
+		}
+
+		/// Sends a command and receives the next line of text in response
+		pub(crate) async fn cmd(&mut self, text: &str) -> String {
+			self.send(text).await;
+			self.recv().await
+		}
+	}
+
+	/// Creates an empty directory for a test to write files to
+	pub(crate) fn test_dir(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("heartsock-test-{}-{}", std::process::id(), name));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	/// Creates a server to connect test clients to
+	pub(crate) fn test_server(config: ServerConfig) -> Server<HeartsockServer> {
+		test_server_with_logs(config, LogForwarder::default())
+	}
+
+	/// Creates a server to connect test clients to that forwards logs from a forwarder
+	pub(crate) fn test_server_with_logs(config: ServerConfig, logs: LogForwarder) -> Server<HeartsockServer> {
+		create_server(Arc::new(config), logs, Arc::default(), None).unwrap()
+	}
+
+	/// Creates a server to connect test clients to that refuses new connections while a flag is set
+	pub(crate) fn test_server_with_draining(
+		config: ServerConfig,
+		draining: Arc<AtomicBool>,
+	) -> Server<HeartsockServer> {
+		create_server(Arc::new(config), LogForwarder::default(), draining, None).unwrap()
+	}
+
+	/// Creates a server to connect test clients to that writes JSON Lines records of value changes to a buffer
+	pub(crate) fn test_server_with_jsonl(config: ServerConfig, jsonl_out: SharedBuffer) -> Server<HeartsockServer> {
+		create_server(
+			Arc::new(config),
+			LogForwarder::default(),
+			Arc::default(),
+			Some(Box::new(jsonl_out)),
+		)
+		.unwrap()
+	}
+
+	/// Listens for real TCP connections to a server on a free loopback port, returning the address it's listening on
+	#[cfg(feature = "simulate")]
+	pub(crate) async fn serve(server: &Server<HeartsockServer>, config: ServerConfig) -> SocketAddr {
+		let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+		let address = listener.local_addr().unwrap();
+		let server = server.clone();
+		tokio::spawn(async move {
+			let draining = AtomicBool::new(false);
+			while let Ok((stream, address)) = listener.accept().await {
+				accept(&server, stream, address, &config, &draining, false).await;
+			}
+		});
+		address
+	}
+
+	/// Buffer that can be written to by a server while a test keeps a handle to read it
+	#[derive(Clone, Default)]
+	pub(crate) struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+	impl SharedBuffer {
+		/// Gets everything written to the buffer so far
+		pub(crate) fn contents(&self) -> String {
+			String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+		}
+	}
+
+	impl Write for SharedBuffer {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.0.lock().unwrap().write(buf)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::harness::*;
+	use super::*;
+
+	#[tokio::test]
+	async fn ping_responds_with_pong() {
+		let server = test_server(ServerConfig::default());
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(client.cmd("ping").await, "pong");
+	}
+
+	#[tokio::test]
+	async fn get_returns_set_value() {
+		let server = test_server(ServerConfig::default());
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(client.cmd("get bpm").await, "bpm: 0");
+		assert_eq!(client.cmd("set bpm 80").await, "ok");
+		assert_eq!(client.cmd("get bpm").await, "bpm: 80");
+	}
+
+	#[tokio::test]
+	async fn get_rejects_unknown_key() {
+		let server = test_server(ServerConfig::default());
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(client.cmd("get nope").await, "error: unknown value key");
+	}
+
+	#[tokio::test]
+	async fn set_is_broadcast_to_other_sessions() {
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		let mut viewer = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(viewer.recv().await, "tracker: 1");
+		assert_eq!(viewer.recv().await, "bpm: 80");
+	}
+
+	#[tokio::test]
+	async fn set_is_rejected_from_second_tracker() {
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		let mut other = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(other.recv().await, "tracker: 1");
+		assert_eq!(other.recv().await, "bpm: 80");
+		assert_eq!(other.cmd("set bpm 90").await, "error: a tracker is already connected");
+		assert_eq!(other.cmd("get bpm").await, "bpm: 80");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn stale_lists_keys_not_updated_in_time() {
+		let server = test_server(ServerConfig {
+			stale_after: Some(Duration::from_secs(10)),
+			..Default::default()
+		});
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(client.cmd("stale").await, "stale: battery,bpm");
+
+		assert_eq!(client.cmd("set bpm 80").await, "ok");
+		tokio::time::advance(Duration::from_secs(9)).await;
+		assert_eq!(client.cmd("set battery 50").await, "ok");
+		assert_eq!(client.cmd("stale").await, "stale: none");
+
+		tokio::time::advance(Duration::from_secs(2)).await;
+		assert_eq!(client.cmd("stale").await, "stale: bpm");
+		assert_eq!(client.cmd("set bpm 80").await, "ok");
+		assert_eq!(client.cmd("stale").await, "stale: none");
+	}
+
+	#[tokio::test]
+	async fn higher_priority_session_takes_over_tracker() {
+		let server = test_server(ServerConfig::default());
+		let mut backup = TestClient::connect(&server).await;
+		let mut primary = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(backup.cmd("set bpm 80 pri=1").await, "ok");
+		assert_eq!(observer.recv().await, "tracker: 1");
+		assert_eq!(observer.recv().await, "bpm: 80");
+
+		// Equal priority keeps the incumbent, and a higher one takes over
+		primary.sync().await;
+		assert_eq!(
+			primary.cmd("claim pri=1").await,
+			"error: a tracker is already connected"
+		);
+		assert_eq!(primary.cmd("claim pri=high").await, "error: invalid priority");
+		assert_eq!(primary.cmd("set bpm 90 pri=10").await, "ok");
+		assert_eq!(observer.recv().await, "bpm: 90");
+		backup.sync().await;
+		assert_eq!(backup.cmd("set bpm 85").await, "error: a tracker is already connected");
+	}
+
+	#[tokio::test]
+	async fn info_shows_captured_headers() {
+		let config = || ServerConfig {
+			capture_headers: vec!["user-agent".to_owned(), "x-device".to_owned()],
+			..Default::default()
+		};
+		let server = test_server(config());
+		let headers = [("user-agent", "Tracker/1.0"), ("x-ignored", "nope")];
+		let mut client = TestClient::upgrade(&server, &config(), &headers).await;
+		assert_eq!(client.cmd("info").await, r#"id: 1 user-agent: "Tracker/1.0""#);
+
+		let mut client = TestClient::upgrade(&server, &config(), &[]).await;
+		assert_eq!(client.cmd("info").await, "id: 2");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn temporary_value_reverts_after_ttl() {
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 200 ttl=5s").await, "ok");
+		assert_eq!(
+			tracker.cmd("set bpm 200 ttl=5d").await,
+			"error: unknown duration unit \"d\""
+		);
+		assert_eq!(observer.recv().await, "tracker: 1");
+		assert_eq!(observer.recv().await, "bpm: 80");
+		assert_eq!(observer.recv().await, "bpm: 200");
+
+		tokio::time::advance(Duration::from_secs(4)).await;
+		assert_eq!(observer.cmd("get bpm").await, "bpm: 200");
+		tokio::time::advance(Duration::from_secs(1)).await;
+		assert_eq!(observer.recv().await, "bpm: 80");
+		assert_eq!(tracker.cmd("get bpm").await, "bpm: 80");
+	}
+
+	#[tokio::test]
+	async fn state_file_reflects_latest_values() {
+		let data_dir = test_dir("state-file");
+		let server = test_server(ServerConfig {
+			data_dir: Some(data_dir.clone()),
+			data_format: DataFormat::Json,
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		let before = unix_millis(SystemTime::now());
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 90").await, "ok");
+		assert_eq!(tracker.cmd("set battery 50").await, "ok");
+		let after = unix_millis(SystemTime::now());
+
+		let state: serde_json::Value =
+			serde_json::from_str(&fs::read_to_string(data_dir.join("state.json")).unwrap()).unwrap();
+		assert_eq!(state["tracker"], true);
+		assert_eq!(state["values"]["bpm"], 90);
+		assert_eq!(state["values"]["battery"], 50);
+		let updated_at = state["updated_at"]["bpm"].as_u64().unwrap();
+		assert!((before..=after).contains(&updated_at));
+		assert!(!data_dir.join("bpm.txt").exists());
+	}
+
+	#[tokio::test]
+	async fn operator_messages_reach_every_session() {
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			..Default::default()
+		});
+		let mut admin = TestClient::connect(&server).await;
+		let mut tracker = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		observer.sync().await;
+		assert_eq!(observer.cmd("broadcast hi").await, "error: not authorized");
+
+		admin.sync().await;
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(
+			admin.cmd("broadcast  Break in\t5 min ").await,
+			"message: Break in 5 min"
+		);
+		assert_eq!(admin.recv().await, "ok");
+		for client in [&mut tracker, &mut observer] {
+			assert_eq!(client.recv().await, "message: Break in 5 min");
+		}
+		assert_eq!(admin.cmd("broadcast \n").await, "error: empty message");
+
+		let long = "é".repeat(MAX_MESSAGE_LEN + 10);
+		admin.send(&format!("broadcast {}", long)).await;
+		let expected = format!("message: {}", &long[..MAX_MESSAGE_LEN * 'é'.len_utf8()]);
+		assert_eq!(observer.recv().await, expected);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn rate_throttles_broadcasts_to_the_session() {
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(observer.recv().await, "tracker: 1");
+		assert_eq!(observer.recv().await, "bpm: 80");
+		assert_eq!(observer.cmd("rate bpm fast").await, "error: invalid rate");
+		assert_eq!(observer.cmd("rate bpm 1").await, "ok");
+
+		// Only the first of a burst of changes is sent right away, and the latest is sent once a second has passed
+		for bpm in 81..=85 {
+			assert_eq!(tracker.cmd(&format!("set bpm {}", bpm)).await, "ok");
+		}
+		assert_eq!(observer.recv().await, "bpm: 81");
+		assert_eq!(observer.cmd("ping").await, "pong");
+		tokio::time::advance(Duration::from_secs(1)).await;
+		assert_eq!(observer.recv().await, "bpm: 85");
+		assert_eq!(observer.cmd("ping").await, "pong");
+
+		// A rate of 0 removes the limit
+		assert_eq!(observer.cmd("rate bpm 0").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 86").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 87").await, "ok");
+		assert_eq!(observer.recv().await, "bpm: 86");
+		assert_eq!(observer.recv().await, "bpm: 87");
+	}
+
+	#[tokio::test]
+	async fn snapshot_now_writes_state_inside_data_dir() {
+		let data_dir = test_dir("snapshot-now");
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			data_dir: Some(data_dir.clone()),
+			..Default::default()
+		});
+		let mut admin = TestClient::connect(&server).await;
+		assert_eq!(admin.cmd("snapshot-now snapshot.json").await, "error: not authorized");
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(admin.cmd("set bpm 80").await, "ok");
+		assert_eq!(admin.cmd("set battery 50").await, "ok");
+
+		let path = data_dir.join("Snapshot.json");
+		assert_eq!(
+			admin.cmd("snapshot-now Snapshot.json").await,
+			format!("snapshot: {}", path.display())
+		);
+		let state: serde_json::Value = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+		assert_eq!(state["tracker"], true);
+		assert_eq!(
+			state["values"],
+			serde_json::json!({ "tracker": 1, "bpm": 80, "battery": 50 })
+		);
+
+		for path in [
+			"/tmp/snapshot.json",
+			"../snapshot.json",
+			"snapshots/../../snapshot.json",
+			".",
+		] {
+			assert_eq!(
+				admin.cmd(&format!("snapshot-now {}", path)).await,
+				"error: snapshot path must be relative to the data directory"
+			);
+		}
+	}
+
+	#[test]
+	fn normalize_command_lowercases_ascii() {
+		assert_eq!(normalize_command("SET BPM 80", false), "set bpm 80");
+		assert_eq!(normalize_command("Auth Token", false), "auth token");
+	}
+
+	#[test]
+	fn normalize_command_leaves_non_ascii_alone() {
+		assert_eq!(normalize_command("GET ÉCLAT", false), "get Éclat");
+	}
+
+	#[test]
+	fn normalize_command_keeps_case_sensitive_args() {
+		assert_eq!(normalize_command("AUTH SeCrEt", true), "auth SeCrEt");
+		assert_eq!(normalize_command("PING", true), "ping");
+	}
+
+	#[test]
+	fn summary_reports_defaults() {
+		let summary = ServerConfig::default().summary();
+		let entries: Vec<&str> = summary.split(' ').collect();
+		assert!(entries.contains(&"admin-auth=no"));
+		assert!(entries.contains(&"acl-tokens=0"));
+		assert!(entries.contains(&"data-dir=none"));
+		assert!(entries.contains(&"broadcast-intervals=none"));
+		assert!(entries.contains(&"keepalive=none"));
+	}
+
+	#[test]
+	fn summary_reports_settings_without_secrets() {
+		let config = ServerConfig {
+			admin_token: Some("hunter2".to_owned()),
+			acl: HashMap::from([("secret".to_owned(), HashSet::from(["bpm".to_owned()]))]),
+			broadcast_intervals: HashMap::from([
+				("bpm".to_owned(), Duration::from_secs(1)),
+				("battery".to_owned(), Duration::from_secs(30)),
+			]),
+			max_sessions: Some(10),
+			..Default::default()
+		};
+		let summary = config.summary();
+		let entries: Vec<&str> = summary.split(' ').collect();
+		assert!(entries.contains(&"admin-auth=yes"));
+		assert!(entries.contains(&"acl-tokens=1"));
+		assert!(entries.contains(&"broadcast-intervals=battery=30s,bpm=1s"));
+		assert!(entries.contains(&"max-sessions=10"));
+		assert!(!summary.contains("hunter2"));
+		assert!(!summary.contains("secret"));
+	}
+
+	#[test]
+	fn is_parse_error_matches_misunderstood_commands() {
+		assert!(is_parse_error("error: unknown input"));
+		assert!(is_parse_error("error: usage: updated <key>"));
+		assert!(is_parse_error("error: invalid duration \"x\""));
+	}
+
+	#[test]
+	fn is_parse_error_ignores_other_errors() {
+		assert!(!is_parse_error("error: a tracker is already connected"));
+		assert!(!is_parse_error(RATE_LIMITED));
+		assert!(!is_parse_error("ok"));
+	}
+
+	#[tokio::test]
+	async fn my_errors_counts_errors_by_kind() {
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(client.recv().await, "tracker: 1");
+		assert_eq!(client.recv().await, "bpm: 80");
+
+		assert!(client.cmd("bogus").await.starts_with("error:"));
+		assert!(client.cmd("set bpm 90").await.starts_with("error:"));
+		assert_eq!(
+			client.cmd("my-errors").await,
+			"my-errors: parse=1 rejected-sets=1 rate-limited=0 other=0"
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn my_errors_counts_origin_rate_limiting() {
+		let server = test_server(ServerConfig {
+			origin_rate_limit: Some(2),
+			..Default::default()
+		});
+		let args = SessionArgs {
+			origin: Some("http://example.com".to_owned()),
+			..Default::default()
+		};
+		let mut client = TestClient::connect_with(&server, args).await;
+		assert_eq!(client.cmd("get bpm").await, "bpm: 0");
+		assert_eq!(client.cmd("get bpm").await, RATE_LIMITED);
+		assert_eq!(client.cmd("set bpm 80").await, RATE_LIMITED);
+
+		tokio::time::advance(ORIGIN_WINDOW).await;
+		assert_eq!(
+			client.cmd("my-errors").await,
+			"my-errors: parse=0 rejected-sets=0 rate-limited=2 other=0"
+		);
+	}
+
+	#[test]
+	fn rejection_reason_shortens_error_responses() {
+		assert_eq!(rejection_reason("error: cas mismatch (current=60)"), "cas-mismatch");
+		assert_eq!(
+			rejection_reason("error: a tracker is already connected"),
+			"a-tracker-is-already-connected"
+		);
+		assert_eq!(rejection_reason(RATE_LIMITED), "rate-limited");
+	}
+
+	#[test]
+	fn rejection_reason_drops_punctuation() {
+		assert_eq!(
+			rejection_reason("error: key \"bpm\" is disabled"),
+			"key-bpm-is-disabled"
+		);
+		assert_eq!(rejection_reason("not an error"), "not-an-error");
+	}
+
+	#[test]
+	fn parse_replay_file_reads_records() {
+		let contents = concat!(
+			r#"{"ts":1000,"key":"bpm","value":70}"#,
+			"\n\n",
+			r#"{"ts":1500,"key":"battery","value":95,"session":1}"#,
+			"\n",
+		);
+		assert_eq!(
+			parse_replay_file(contents),
+			vec![(1000, "bpm".to_owned(), 70), (1500, "battery".to_owned(), 95)]
+		);
+	}
+
+	#[test]
+	fn parse_replay_file_skips_invalid_records() {
+		let contents = concat!(
+			"not json\n",
+			r#"{"ts":1000,"key":"tracker","value":1}"#,
+			"\n",
+			r#"{"ts":1000,"key":"bpm","value":300}"#,
+			"\n",
+			r#"{"key":"bpm","value":70}"#,
+			"\n",
+			r#"{"ts":2000,"key":"bpm","value":71}"#,
+		);
+		assert_eq!(parse_replay_file(contents), vec![(2000, "bpm".to_owned(), 71)]);
+	}
+
+	#[test]
+	fn parse_set_options_parses_timestamp() {
+		let options = parse_set_options(&["ts=1700000000000"]).unwrap();
+		assert_eq!(options.timestamp, Some(1_700_000_000_000));
+		assert_eq!(parse_set_options(&["ts=soon"]).unwrap_err(), "invalid timestamp");
+		assert_eq!(parse_set_options(&["ts=-1"]).unwrap_err(), "invalid timestamp");
+	}
+
+	#[tokio::test]
+	async fn set_rejects_out_of_order_timestamps() {
+		let server = test_server(ServerConfig {
+			monotonic_timestamps: true,
+			..Default::default()
+		});
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(client.cmd("set bpm 80 ts=2000").await, "ok");
+		assert_eq!(client.cmd("set bpm 70 ts=1000").await, "error: stale update");
+		assert_eq!(client.cmd("get bpm").await, "bpm: 80");
+	}
+
+	#[tokio::test]
+	async fn values_are_restored_from_text_files() {
+		let data_dir = test_dir("restore-text");
+		fs::write(data_dir.join("bpm.txt"), "72\n").unwrap();
+		fs::write(data_dir.join("battery.txt"), "full").unwrap();
+		let server = test_server(ServerConfig {
+			data_dir: Some(data_dir),
+			restore_from_data_dir: true,
+			..Default::default()
+		});
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(client.cmd("get bpm").await, "bpm: 72");
+		assert_eq!(client.cmd("get battery").await, "battery: 0");
+	}
+
+	#[tokio::test]
+	async fn values_are_restored_from_state_file() {
+		let data_dir = test_dir("restore-json");
+		let state = serde_json::json!({ "values": { "bpm": 300, "battery": 64 } });
+		fs::write(data_dir.join("state.json"), state.to_string()).unwrap();
+		fs::write(data_dir.join("bpm.txt"), "72").unwrap();
+		let server = test_server(ServerConfig {
+			data_dir: Some(data_dir.clone()),
+			data_format: DataFormat::Json,
+			restore_from_data_dir: true,
+			..Default::default()
+		});
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(client.cmd("get bpm").await, "bpm: 0");
+		assert_eq!(client.cmd("get battery").await, "battery: 64");
+
+		// Nothing is restored unless it's enabled
+		let server = test_server(ServerConfig {
+			data_dir: Some(data_dir),
+			data_format: DataFormat::Json,
+			..Default::default()
+		});
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(client.cmd("get battery").await, "battery: 0");
+	}
+
+	#[tokio::test]
+	async fn device_id_closes_older_duplicate() {
+		let server = test_server(ServerConfig {
+			dedupe_devices: true,
+			..Default::default()
+		});
+		let mut old = TestClient::connect(&server).await;
+		assert_eq!(old.cmd("device-id watch").await, "ok");
+		assert_eq!(old.cmd("set bpm 80").await, "ok");
+
+		let mut new = TestClient::connect(&server).await;
+		assert_eq!(new.cmd("device-id watch").await, "tracker: 0");
+		assert_eq!(new.recv().await, "ok");
+		assert_eq!(old.recv_close().await, "replaced by a newer connection");
+		assert_eq!(new.cmd("set bpm 90").await, "ok");
+	}
+
+	#[tokio::test]
+	async fn device_id_is_rejected_from_readonly_sessions() {
+		let server = test_server(ServerConfig {
+			dedupe_devices: true,
+			skip_snapshot_on_connect: true,
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("device-id watch").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+
+		let args = SessionArgs {
+			readonly: true,
+			..Default::default()
+		};
+		let mut readonly = TestClient::open(&server, args).await;
+		assert_eq!(
+			readonly.cmd(r#"{"handshake": true, "device_id": "watch"}"#).await,
+			"error: read-only connection"
+		);
+		assert_eq!(readonly.cmd("device-id watch").await, "error: read-only connection");
+		assert_eq!(tracker.cmd("get tracker").await, "tracker: 1");
+	}
+
+	#[tokio::test]
+	async fn device_id_only_closes_duplicates_for_sessions_that_may_claim() {
+		let server = test_server(ServerConfig {
+			dedupe_devices: true,
+			acl: HashMap::from([("secret".to_owned(), HashSet::from(["bpm".to_owned()]))]),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("auth secret").await, "ok");
+		assert_eq!(tracker.cmd("device-id watch").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+
+		let mut other = TestClient::connect(&server).await;
+		assert_eq!(other.cmd("device-id watch").await, "ok");
+		assert_eq!(tracker.cmd("get tracker").await, "tracker: 1");
+		assert_eq!(tracker.cmd("set bpm 81").await, "ok");
+	}
+
+	#[tokio::test]
+	async fn pinned_tracker_ip_rejects_sets_from_other_ips() {
+		let server = test_server(ServerConfig {
+			pin_tracker_ip: true,
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect_from(&server, [10, 0, 0, 1]).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		tracker.close().await;
+
+		let mut other = TestClient::connect_from(&server, [10, 0, 0, 2]).await;
+		assert_eq!(other.cmd("set bpm 90").await, "error: not from the tracker's IP");
+		let mut reconnected = TestClient::connect_from(&server, [10, 0, 0, 1]).await;
+		assert_eq!(reconnected.cmd("set bpm 90").await, "ok");
+	}
+
+	#[tokio::test]
+	async fn pinned_tracker_ip_drops_backups_from_other_ips() {
+		let server = test_server(ServerConfig {
+			pin_tracker_ip: true,
+			..Default::default()
+		});
+		let mut backup = TestClient::connect_from(&server, [10, 0, 0, 2]).await;
+		assert_eq!(backup.cmd("claim backup").await, "ok");
+		let mut tracker = TestClient::connect_from(&server, [10, 0, 0, 1]).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(backup.recv().await, "tracker: 1");
+		assert_eq!(backup.recv().await, "bpm: 80");
+
+		tracker.close().await;
+		assert_eq!(backup.recv().await, "tracker: 0");
+		assert_eq!(backup.cmd("claim").await, "error: not from the tracker's IP");
+	}
+
+	#[tokio::test]
+	async fn logged_out_tracker_is_replaced_by_another_authenticated_session() {
+		let server = test_server(ServerConfig {
+			acl: HashMap::from([("secret".to_owned(), HashSet::from(["bpm".to_owned()]))]),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		let mut other = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("auth secret").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(other.recv().await, "tracker: 1");
+		assert_eq!(other.recv().await, "bpm: 80");
+
+		assert_eq!(tracker.cmd("logout").await, "tracker: 0");
+		assert_eq!(tracker.recv().await, "ok");
+		assert_eq!(other.recv().await, "tracker: 0");
+		assert_eq!(tracker.cmd("set bpm 90").await, "error: not permitted for key");
+		assert_eq!(other.cmd("auth secret").await, "ok");
+		assert_eq!(other.cmd("set bpm 90").await, "ok");
+	}
+
+	#[tokio::test]
+	async fn logout_drops_events_and_backup_role() {
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		let mut admin = TestClient::connect(&server).await;
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(admin.cmd("events on").await, "ok");
+		assert_eq!(admin.cmd("claim backup").await, "ok");
+
+		assert_eq!(admin.cmd("logout").await, "ok");
+		let _observer = TestClient::connect(&server).await;
+		tracker.close().await;
+		assert_eq!(admin.recv().await, "tracker: 0");
+		assert_eq!(admin.cmd("ping").await, "pong");
+	}
+
+	#[tokio::test]
+	async fn replay_sends_recorded_values_to_observers() {
+		let data_dir = test_dir("replay");
+		let recording = [
+			r#"{"ts":1000,"key":"bpm","value":70}"#,
+			r#"{"ts":1010,"key":"battery","value":95}"#,
+			r#"{"ts":1020,"key":"bpm","value":72}"#,
+		];
+		std::fs::write(data_dir.join("recording.jsonl"), recording.join("\n")).unwrap();
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			data_dir: Some(data_dir),
+			..Default::default()
+		});
+		let mut admin = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(admin.cmd("replay recording.jsonl").await, "ok: replaying 3 values");
+		assert_eq!(observer.recv().await, "bpm: 70");
+		assert_eq!(observer.recv().await, "battery: 95");
+		assert_eq!(observer.recv().await, "bpm: 72");
+	}
+
+	#[tokio::test]
+	async fn replay_stops_when_delay_is_too_long() {
+		let data_dir = test_dir("replay-slow");
+		let recording = [
+			r#"{"ts":1000,"key":"bpm","value":70}"#,
+			r#"{"ts":2000,"key":"bpm","value":72}"#,
+		];
+		std::fs::write(data_dir.join("recording.jsonl"), recording.join("\n")).unwrap();
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			data_dir: Some(data_dir),
+			..Default::default()
+		});
+		let mut admin = TestClient::connect(&server).await;
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(
+			admin.cmd("replay recording.jsonl 1e-300").await,
+			"ok: replaying 2 values"
+		);
+		assert_eq!(admin.recv().await, "bpm: 70");
+		assert_eq!(admin.cmd("ping").await, "pong");
+	}
+
+	#[tokio::test]
+	async fn simultaneous_sets_promote_exactly_one_tracker() {
+		let server = test_server(ServerConfig::default());
+		let mut clients = Vec::new();
+		for _ in 0..32 {
+			clients.push(TestClient::connect(&server).await);
+		}
+
+		// Send every set before reading any responses, skipping the broadcasts of the winner's set
+		let sets = clients.iter_mut().enumerate().map(|(i, client)| async move {
+			client.send(&format!("set bpm {}", 100 + i)).await;
+			loop {
+				let line = client.recv().await;
+				if line == "ok" || line.starts_with("error") {
+					return line;
+				}
+			}
+		});
+		let responses = futures_util::future::join_all(sets).await;
+
+		let winners: Vec<usize> = (0..responses.len()).filter(|i| responses[*i] == "ok").collect();
+		assert_eq!(winners.len(), 1);
+		for (i, response) in responses.iter().enumerate() {
+			if i != winners[0] {
+				assert_eq!(response, "error: a tracker is already connected");
+			}
+		}
+		assert_eq!(clients[0].cmd("get bpm").await, format!("bpm: {}", 100 + winners[0]));
+		assert_eq!(clients[winners[0]].cmd("set bpm 60").await, "ok");
+	}
+
+	#[tokio::test]
+	async fn smoothed_broadcasts_follow_ema() {
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			smoothing: HashMap::from([("bpm".to_owned(), 0.5)]),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 100").await, "ok");
+		assert_eq!(observer.recv().await, "tracker: 1");
+		assert_eq!(observer.recv().await, "bpm: 100");
+		assert_eq!(tracker.cmd("set bpm 60").await, "ok");
+		assert_eq!(observer.recv().await, "bpm: 80");
+		assert_eq!(tracker.cmd("set bpm 60").await, "ok");
+		assert_eq!(observer.recv().await, "bpm: 70");
+
+		assert_eq!(observer.cmd("get bpm").await, "bpm: 70");
+		let state = observer.cmd("state").await;
+		let state: serde_json::Value = serde_json::from_str(state.strip_prefix("state: ").unwrap()).unwrap();
+		assert_eq!(state["bpm"]["value"], 70);
+	}
+
+	#[tokio::test]
+	async fn pinned_values_are_not_smoothed() {
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			smoothing: HashMap::from([("bpm".to_owned(), 0.3)]),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		let mut admin = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 120").await, "ok");
+		assert_eq!(admin.recv().await, "tracker: 1");
+		assert_eq!(admin.recv().await, "bpm: 120");
+
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(admin.cmd("pin bpm 70").await, "bpm: 70");
+		assert_eq!(admin.recv().await, "ok");
+		assert_eq!(admin.cmd("get bpm").await, "bpm: 70");
+	}
+
+	#[tokio::test]
+	async fn disabled_key_rejects_sets_until_enabled() {
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		let mut admin = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set battery 90").await, "ok");
+		assert_eq!(admin.recv().await, "tracker: 1");
+		assert_eq!(admin.recv().await, "battery: 90");
+
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(admin.cmd("disable-key battery").await, "ok");
+		assert_eq!(tracker.cmd("set battery 80").await, "error: key disabled");
+		assert_eq!(admin.cmd("pin battery 70").await, "error: key disabled");
+		assert_eq!(tracker.cmd("set bpm 60").await, "ok");
+		assert_eq!(admin.recv().await, "bpm: 60");
+
+		assert_eq!(admin.cmd("enable-key battery").await, "battery: 90");
+		assert_eq!(admin.recv().await, "ok");
+		assert_eq!(tracker.cmd("set battery 80").await, "ok");
+		assert_eq!(admin.recv().await, "battery: 80");
+	}
+
+	#[tokio::test]
+	async fn disabled_key_ignores_replayed_values() {
+		let data_dir = test_dir("replay-disabled");
+		let recording = [
+			r#"{"ts":1000,"key":"battery","value":50}"#,
+			r#"{"ts":1001,"key":"bpm","value":70}"#,
+		];
+		std::fs::write(data_dir.join("recording.jsonl"), recording.join("\n")).unwrap();
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			data_dir: Some(data_dir),
+			..Default::default()
+		});
+		let mut admin = TestClient::connect(&server).await;
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(admin.cmd("disable-key battery").await, "ok");
+		assert_eq!(admin.cmd("replay recording.jsonl").await, "ok: replaying 2 values");
+		assert_eq!(admin.recv().await, "bpm: 70");
+		assert_eq!(admin.cmd("get battery").await, "battery: 0");
+	}
+
+	#[tokio::test]
+	async fn rejections_are_counted_and_reset() {
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		let mut admin = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(admin.recv().await, "tracker: 1");
+		assert_eq!(admin.recv().await, "bpm: 80");
+
+		assert_eq!(admin.cmd("set bpm 90").await, "error: a tracker is already connected");
+		assert_eq!(admin.cmd("set bpm 91").await, "error: a tracker is already connected");
+		assert_eq!(tracker.cmd("cas bpm 70 90").await, "error: cas mismatch (current=80)");
+		assert!(admin.cmd("get nope").await.starts_with("error:"));
+		assert!(admin.cmd("set nope 1").await.starts_with("error:"));
+
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(
+			admin.cmd("rejections").await,
+			"rejections: total=3 a-tracker-is-already-connected=2 cas-mismatch=1"
+		);
+		assert_eq!(admin.cmd("reset-rejections").await, "ok");
+		assert_eq!(admin.cmd("rejections").await, "rejections: total=0");
+	}
+
+	#[tokio::test]
+	async fn rejections_only_count_rate_limited_sets() {
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			origin_rate_limit: Some(3),
+			..Default::default()
+		});
+		let args = SessionArgs {
+			origin: Some("http://example.com".to_owned()),
+			..Default::default()
+		};
+		let mut client = TestClient::connect_with(&server, args).await;
+		let mut admin = TestClient::connect(&server).await;
+		assert_eq!(client.cmd("get bpm").await, "bpm: 0");
+		assert_eq!(client.cmd("get bpm").await, "bpm: 0");
+		assert_eq!(client.cmd("get bpm").await, RATE_LIMITED);
+		assert_eq!(client.cmd("set bpm 80").await, RATE_LIMITED);
+
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(admin.cmd("rejections").await, "rejections: total=1 rate-limited=1");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn broadcast_interval_coalesces_battery_but_not_bpm() {
+		let server = test_server(ServerConfig {
+			broadcast_intervals: HashMap::from([("battery".to_owned(), Duration::from_secs(1))]),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set battery 90").await, "ok");
+		assert_eq!(tracker.cmd("set battery 85").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 70").await, "ok");
+		assert_eq!(tracker.cmd("set battery 80").await, "ok");
+		assert_eq!(observer.recv().await, "tracker: 1");
+		assert_eq!(observer.recv().await, "bpm: 70");
+
+		// Only the latest battery value goes out once the interval is up
+		assert_eq!(observer.recv().await, "battery: 80");
+		assert_eq!(observer.cmd("ping").await, "pong");
+	}
+
+	#[tokio::test]
+	async fn session_state_reflects_toggled_options() {
+		let server = test_server(ServerConfig {
+			acl: HashMap::from([(
+				"secret".to_owned(),
+				HashSet::from(["bpm".to_owned(), "battery".to_owned()]),
+			)]),
+			..Default::default()
+		});
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(
+			client.cmd("session-state").await,
+			"session-state: id=1 admin=no readonly=no echo=no quiet=no permitted=none role=observer muted=none \
+			 rates=none events=off"
+		);
+
+		assert_eq!(client.cmd("auth secret").await, "ok");
+		assert_eq!(client.cmd("quiet on").await, "ok");
+		assert_eq!(client.cmd("mute battery").await, "ok");
+		assert_eq!(client.cmd("rate bpm 2").await, "ok");
+		client.send("set bpm 80").await;
+		assert_eq!(
+			client.cmd("session-state").await,
+			"session-state: id=1 admin=no readonly=no echo=no quiet=yes permitted=battery,bpm role=tracker \
+			 muted=battery rates=bpm:500ms events=off"
+		);
+	}
+
+	#[tokio::test]
+	async fn updates_reach_many_observers_promptly() {
+		let server = test_server(ServerConfig::default());
+		let mut observers = Vec::new();
+		for _ in 0..100 {
+			observers.push(TestClient::connect(&server).await);
+		}
+		let mut tracker = TestClient::connect(&server).await;
+
+		let started = Instant::now();
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		for observer in &mut observers {
+			assert_eq!(observer.recv().await, "tracker: 1");
+			assert_eq!(observer.recv().await, "bpm: 80");
+		}
+		assert!(started.elapsed() < Duration::from_secs(1));
+	}
+
+	#[tokio::test]
+	async fn disconnecting_an_already_removed_session_is_harmless() {
+		let server = test_server(ServerConfig {
+			dedupe_devices: true,
+			..Default::default()
+		});
+		let mut old = TestClient::connect(&server).await;
+		assert_eq!(old.cmd("device-id watch").await, "ok");
+
+		// The older session is removed as soon as it's replaced, then disconnects for real once it closes
+		let mut new = TestClient::connect(&server).await;
+		assert_eq!(new.cmd("device-id watch").await, "ok");
+		assert_eq!(old.recv_close().await, "replaced by a newer connection");
+		drop(old);
+
+		assert_eq!(
+			new.cmd("capacity").await,
+			"capacity: sessions=1 max=none utilization=0%"
+		);
+		let mut other = TestClient::connect(&server).await;
+		assert_eq!(
+			other.cmd("capacity").await,
+			"capacity: sessions=2 max=none utilization=0%"
+		);
+	}
+
+	#[tokio::test]
+	async fn native_ping_frames_get_pongs() {
+		let server = test_server(ServerConfig::default());
+		let mut client = TestClient::connect(&server).await;
+		client.send_frame(tungstenite::Message::Ping(b"rtt".to_vec())).await;
+		assert_eq!(client.recv_frame().await, tungstenite::Message::Pong(b"rtt".to_vec()));
+		assert_eq!(client.cmd("ping").await, "pong");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn server_sends_heartbeat_pings() {
+		let server = test_server(ServerConfig::default());
+		let mut client = TestClient::connect(&server).await;
+		tokio::time::advance(Duration::from_secs(5)).await;
+		assert!(matches!(client.recv_frame().await, tungstenite::Message::Ping(_)));
+		assert_eq!(client.cmd("ping").await, "pong");
+	}
+
+	#[tokio::test]
+	async fn setting_a_key_without_a_value_does_not_panic() {
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+
+		let response = server
+			.call_with(|respond_to| Message::SetVal {
+				id: 1,
+				key: "spo2".to_owned(),
+				val: 97,
+				options: SetOptions::default(),
+				respond_to,
+			})
+			.await;
+		assert_eq!(response, "ok");
+		assert_eq!(observer.recv().await, "tracker: 1");
+		assert_eq!(observer.recv().await, "spo2: 97");
+
+		let response = server
+			.call_with(|respond_to| Message::GetVal {
+				key: "spo2".to_owned(),
+				respond_to,
+			})
+			.await;
+		assert_eq!(response, "spo2: 97");
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+	}
+
+	#[tokio::test]
+	async fn echo_prefixes_responses_with_commands() {
+		let server = test_server(ServerConfig::default());
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(client.cmd("set bpm 120").await, "ok");
+		assert_eq!(client.cmd("echo on").await, "[echo on] ok");
+		assert_eq!(client.cmd("set bpm 121").await, "[set bpm 121] ok");
+		assert_eq!(client.cmd("get bpm").await, "[get bpm] bpm: 121");
+		assert_eq!(client.cmd("echo off").await, "ok");
+		assert_eq!(client.cmd("get bpm").await, "bpm: 121");
+
+		let server = test_server(ServerConfig {
+			echo_commands: true,
+			..Default::default()
+		});
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(client.cmd("set bpm 120").await, "[set bpm 120] ok");
+	}
+
+	#[tokio::test]
+	async fn first_setter_becomes_tracker_by_default() {
+		let server = test_server(ServerConfig::default());
+		let mut first = TestClient::connect(&server).await;
+		let mut second = TestClient::connect(&server).await;
+		assert_eq!(first.cmd("set bpm 80").await, "ok");
+		assert_eq!(second.recv().await, "tracker: 1");
+		assert_eq!(second.recv().await, "bpm: 80");
+		assert_eq!(second.cmd("set bpm 90").await, "error: a tracker is already connected");
+	}
+
+	#[tokio::test]
+	async fn explicit_tracker_requires_a_claim_before_setting() {
+		let server = test_server(ServerConfig {
+			explicit_tracker: true,
+			..Default::default()
+		});
+		let mut first = TestClient::connect(&server).await;
+		let mut second = TestClient::connect(&server).await;
+		assert_eq!(first.cmd("set bpm 80").await, "error: no tracker claimed");
+		assert_eq!(first.cmd("claim").await, "ok");
+		assert_eq!(second.recv().await, "tracker: 1");
+		assert_eq!(first.cmd("set bpm 80").await, "ok");
+		assert_eq!(second.recv().await, "bpm: 80");
+		assert_eq!(second.cmd("set bpm 90").await, "error: a tracker is already connected");
+		assert_eq!(second.cmd("claim").await, "error: a tracker is already connected");
+	}
+
+	#[tokio::test]
+	async fn server_version_reports_package_version() {
+		let server = test_server(ServerConfig::default());
+		let mut client = TestClient::connect(&server).await;
+		let response = client.cmd("server-version").await;
+		let prefix = format!("server-version: {}", env!("CARGO_PKG_VERSION"));
+		assert!(response.starts_with(&prefix), "{}", response);
+	}
+
+	#[tokio::test]
+	async fn capabilities_and_state_include_units() {
+		let server = test_server(ServerConfig::default());
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(
+			client.cmd("capabilities").await,
+			r#"capabilities: bpm unit="BPM" label="Heart rate", battery unit="%" label="Battery""#
+		);
+
+		let state = client.cmd("state").await;
+		let state: serde_json::Value = serde_json::from_str(state.strip_prefix("state: ").unwrap()).unwrap();
+		assert_eq!(state["battery"]["unit"], "%");
+		assert_eq!(state["bpm"]["unit"], "BPM");
+		assert_eq!(state["tracker"]["unit"], serde_json::Value::Null);
+	}
+
+	#[tokio::test]
+	async fn distinguish_unset_reports_unset_until_first_set() {
+		let server = test_server(ServerConfig {
+			distinguish_unset: true,
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(observer.cmd("get bpm").await, "bpm: unset");
+		assert_eq!(tracker.cmd("set bpm 0").await, "ok");
+		assert_eq!(observer.recv().await, "tracker: 1");
+		assert_eq!(observer.recv().await, "bpm: 0");
+		assert_eq!(observer.cmd("get bpm").await, "bpm: 0");
+		assert_eq!(observer.cmd("get battery").await, "battery: unset");
+
+		let server = test_server(ServerConfig::default());
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(observer.cmd("get bpm").await, "bpm: 0");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn data_flush_interval_rewrites_unchanged_files() {
+		let data_dir = test_dir("data-flush");
+		let server = test_server(ServerConfig {
+			data_dir: Some(data_dir.clone()),
+			data_flush_interval: Some(Duration::from_secs(10)),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+
+		// Backdate the file, so it's clear when it's been rewritten
+		let path = data_dir.join("bpm.txt");
+		let backdated = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+		fs::File::options()
+			.write(true)
+			.open(&path)
+			.unwrap()
+			.set_modified(backdated)
+			.unwrap();
+
+		tokio::time::advance(Duration::from_secs(10)).await;
+		tracker.sync().await;
+		assert!(fs::metadata(&path).unwrap().modified().unwrap() > backdated);
+		assert_eq!(fs::read_to_string(&path).unwrap(), "80");
+	}
+
+	#[tokio::test]
+	async fn broadcasting_to_closed_sessions_cleans_them_up() {
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		let observer = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+
+		// Drop the connection without closing it, then broadcast while the session may still be in the map
+		drop(observer);
+		for bpm in 81..=90 {
+			assert_eq!(tracker.cmd(&format!("set bpm {}", bpm)).await, "ok");
+		}
+		assert_eq!(
+			tracker.cmd("capacity").await,
+			"capacity: sessions=1 max=none utilization=0%"
+		);
+	}
+
+	#[tokio::test]
+	async fn peaks_track_extremes_until_reset() {
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("peaks bpm").await, "error: no bpm values recorded");
+		for bpm in [80, 58, 172, 100] {
+			assert_eq!(tracker.cmd(&format!("set bpm {}", bpm)).await, "ok");
+		}
+		assert_eq!(tracker.cmd("peaks bpm").await, "min: 58 max: 172");
+		assert_eq!(tracker.cmd("peaks tracker").await, "error: unknown value key");
+
+		assert_eq!(tracker.cmd("reset-stats").await, "error: not authorized");
+		assert_eq!(tracker.cmd("auth admin").await, "ok");
+		assert_eq!(tracker.cmd("reset-stats").await, "ok");
+		assert_eq!(tracker.cmd("peaks bpm").await, "error: no bpm values recorded");
+		assert_eq!(tracker.cmd("set bpm 90").await, "ok");
+		assert_eq!(tracker.cmd("peaks bpm").await, "min: 90 max: 90");
+	}
+
+	#[tokio::test]
+	async fn readonly_sessions_can_only_observe() {
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		let args = SessionArgs {
+			readonly: true,
+			..Default::default()
+		};
+		let mut readonly = TestClient::connect_with(&server, args).await;
+
+		assert_eq!(readonly.cmd("set bpm 80").await, "error: read-only connection");
+		assert_eq!(readonly.cmd("claim").await, "error: read-only connection");
+		assert_eq!(readonly.cmd("get tracker").await, "tracker: 0");
+
+		assert_eq!(tracker.cmd("set bpm 90").await, "ok");
+		assert_eq!(readonly.recv().await, "tracker: 1");
+		assert_eq!(readonly.recv().await, "bpm: 90");
+	}
+
+	#[tokio::test]
+	async fn cas_only_applies_matching_values() {
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(tracker.cmd("cas bpm 80 90").await, "ok");
+		assert_eq!(tracker.cmd("cas bpm 80 100").await, "error: cas mismatch (current=90)");
+		assert_eq!(observer.recv().await, "tracker: 1");
+		assert_eq!(observer.recv().await, "bpm: 80");
+		assert_eq!(observer.recv().await, "bpm: 90");
+		assert_eq!(observer.cmd("get bpm").await, "bpm: 90");
+	}
+
+	#[tokio::test]
+	async fn subscribed_admin_receives_connect_and_disconnect_events() {
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			..Default::default()
+		});
+		let mut admin = TestClient::connect(&server).await;
+		assert_eq!(admin.cmd("events on").await, "error: not authorized");
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(admin.cmd("events on").await, "ok");
+
+		let client = TestClient::connect_from(&server, [192, 168, 1, 20]).await;
+		assert_eq!(admin.recv().await, "connected 2 192.168.1.20:0");
+		client.close().await;
+		assert_eq!(admin.recv().await, "disconnected 2");
+
+		assert_eq!(admin.cmd("events off").await, "ok");
+		let _client = TestClient::connect(&server).await;
+		assert_eq!(admin.cmd("ping").await, "pong");
+	}
+
+	#[tokio::test]
+	async fn placeholder_is_broadcast_while_there_is_no_tracker() {
+		let server = test_server(ServerConfig {
+			no_tracker_placeholder: Some("--".to_owned()),
+			..Default::default()
+		});
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(observer.cmd("get bpm").await, "bpm: --");
+
+		let mut tracker = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		for line in ["bpm: 0", "battery: 0", "tracker: 1", "bpm: 80"] {
+			assert_eq!(observer.recv().await, line);
+		}
+
+		tracker.close().await;
+		for line in ["tracker: 0", "bpm: --", "battery: --"] {
+			assert_eq!(observer.recv().await, line);
+		}
+	}
+
+	#[tokio::test]
+	async fn muted_keys_stop_arriving_until_unmuted() {
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(observer.recv().await, "tracker: 1");
+		assert_eq!(observer.recv().await, "bpm: 80");
+
+		assert_eq!(observer.cmd("mute bpm").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 90").await, "ok");
+		assert_eq!(tracker.cmd("set battery 50").await, "ok");
+		assert_eq!(observer.recv().await, "battery: 50");
+
+		assert_eq!(observer.cmd("unmute bpm").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 100").await, "ok");
+		assert_eq!(observer.recv().await, "bpm: 100");
+	}
+
+	#[tokio::test]
+	async fn default_key_is_used_when_commands_leave_out_the_key() {
+		let server = test_server(ServerConfig {
+			default_key: Some("bpm".to_owned()),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set 120").await, "ok");
+		assert_eq!(tracker.cmd("get").await, "bpm: 120");
+		assert_eq!(tracker.cmd("set battery 50").await, "ok");
+		assert_eq!(tracker.cmd("get battery").await, "battery: 50");
+
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set 120").await, "error: unknown value key");
+		assert_eq!(tracker.cmd("get bpm").await, "bpm: 0");
+	}
+
+	#[tokio::test]
+	async fn quiet_mode_only_skips_acknowledgements_of_sets() {
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("quiet on").await, "ok");
+		tracker.send("set bpm 80").await;
+		assert_eq!(tracker.cmd("set bpm 300").await, "error: unknown input for bpm value");
+		assert_eq!(tracker.cmd("get bpm").await, "bpm: 80");
+
+		assert_eq!(tracker.cmd("quiet off").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 85").await, "ok");
+	}
+
+	#[tokio::test]
+	async fn whereami_reports_the_client_address() {
+		let server = test_server(ServerConfig::default());
+		let address = SocketAddr::from(([203, 0, 113, 7], 51234));
+		let mut client = TestClient::open_from(&server, address, SessionArgs::default()).await;
+		client.sync().await;
+		assert_eq!(client.cmd("whereami").await, "address: 203.0.113.7:51234");
+	}
+
+	#[tokio::test]
+	async fn value_changes_are_written_as_json_lines() {
+		let jsonl_out = SharedBuffer::default();
+		let server = test_server_with_jsonl(ServerConfig::default(), jsonl_out.clone());
+		let mut tracker = TestClient::connect(&server).await;
+		let before = unix_millis(SystemTime::now());
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(tracker.cmd("set battery 50").await, "ok");
+		let after = unix_millis(SystemTime::now());
+
+		let records: Vec<serde_json::Value> = jsonl_out
+			.contents()
+			.lines()
+			.map(|line| serde_json::from_str(line).unwrap())
+			.collect();
+		let changes: Vec<(&str, u64, bool)> = records
+			.iter()
+			.map(|record| {
+				let ts = record["ts"].as_u64().unwrap();
+				assert!((before..=after).contains(&ts));
+				(
+					record["key"].as_str().unwrap(),
+					record["value"].as_u64().unwrap(),
+					record["tracker"].as_bool().unwrap(),
+				)
+			})
+			.collect();
+		assert_eq!(
+			changes,
+			[("tracker", 1, true), ("bpm", 80, true), ("battery", 50, true)]
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn tracker_uptime_counts_from_promotion() {
+		let server = test_server(ServerConfig::default());
+		let mut first = TestClient::connect(&server).await;
+		let mut second = TestClient::connect(&server).await;
+		assert_eq!(first.cmd("tracker-uptime").await, "tracker-uptime: none");
+		assert_eq!(first.cmd("set bpm 80").await, "ok");
+		tokio::time::advance(Duration::from_secs(30)).await;
+		assert_eq!(first.cmd("tracker-uptime").await, "tracker-uptime: 30");
+
+		// Handing off to another session starts the count over
+		second.sync().await;
+		assert_eq!(second.cmd("claim pri=1").await, "ok");
+		assert_eq!(second.cmd("tracker-uptime").await, "tracker-uptime: 0");
+		tokio::time::advance(Duration::from_secs(5)).await;
+		assert_eq!(second.cmd("tracker-uptime").await, "tracker-uptime: 5");
+	}
+
+	#[tokio::test]
+	async fn sessions_per_ip_are_limited() {
+		let server = test_server(ServerConfig {
+			max_sessions_per_ip: Some(2),
+			..Default::default()
+		});
+		let _first = TestClient::connect_from(&server, [192, 168, 1, 20]).await;
+		let _second = TestClient::connect_from(&server, [192, 168, 1, 20]).await;
+		let address = SocketAddr::from(([192, 168, 1, 20], 0));
+		let mut refused = TestClient::open_from(&server, address, SessionArgs::default()).await;
+		assert_eq!(refused.recv().await, "error: too many connections from your address");
+		assert_eq!(refused.recv_close().await, "too many connections");
+
+		let mut other = TestClient::connect_from(&server, [192, 168, 1, 21]).await;
+		assert_eq!(
+			other.cmd("capacity").await,
+			"capacity: sessions=3 max=none utilization=0%"
+		);
+	}
+
+	#[tokio::test]
+	async fn sync_version_increases_with_each_change() {
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(observer.cmd("sync").await, "sync: version=0 battery=0 bpm=0 tracker=0");
+
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		observer.sync().await;
+		assert_eq!(observer.cmd("sync").await, "sync: version=2 battery=0 bpm=80 tracker=1");
+
+		// Sets that don't change anything leave the version alone
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(observer.cmd("sync").await, "sync: version=2 battery=0 bpm=80 tracker=1");
+		assert_eq!(tracker.cmd("set battery 50").await, "ok");
+		observer.sync().await;
+		assert_eq!(
+			observer.cmd("sync").await,
+			"sync: version=3 battery=50 bpm=80 tracker=1"
+		);
+	}
+
+	#[tokio::test]
+	async fn draining_refuses_new_connections_but_keeps_existing_ones() {
+		let config = || ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			..Default::default()
+		};
+		let draining = Arc::new(AtomicBool::new(false));
+		let server = test_server_with_draining(config(), draining.clone());
+		let mut admin = TestClient::try_upgrade(&server, &config(), &draining, &[])
+			.await
+			.unwrap();
+		let mut tracker = TestClient::try_upgrade(&server, &config(), &draining, &[])
+			.await
+			.unwrap();
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		admin.sync().await;
+		assert_eq!(admin.cmd("drain").await, "error: not authorized");
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(admin.cmd("drain").await, "draining");
+		assert_eq!(admin.recv().await, "ok");
+		assert_eq!(tracker.recv().await, "draining");
+		assert_eq!(admin.cmd("drain").await, "error: already draining");
+
+		match TestClient::try_upgrade(&server, &config(), &draining, &[]).await {
+			Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE),
+			Err(err) => panic!("unexpected error: {}", err),
+			Ok(_) => panic!("connection wasn't refused"),
+		}
+		assert_eq!(tracker.cmd("set bpm 90").await, "ok");
+		assert_eq!(admin.recv().await, "bpm: 90");
+	}
+
+	#[tokio::test]
+	async fn pinned_key_ignores_tracker_sets_until_unpinned() {
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		let mut admin = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		admin.sync().await;
+		assert_eq!(admin.cmd("pin bpm 70").await, "error: not authorized");
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(admin.cmd("pin bpm 70").await, "bpm: 70");
+		assert_eq!(admin.recv().await, "ok");
+
+		assert_eq!(tracker.cmd("set bpm 90").await, "ok (pinned, not applied)");
+		assert_eq!(tracker.cmd("get bpm").await, "bpm: 70");
+
+		assert_eq!(admin.cmd("unpin bpm").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 95").await, "ok");
+		assert_eq!(admin.recv().await, "bpm: 95");
+	}
+
+	#[tokio::test]
+	async fn id_is_sent_first_on_connect_when_enabled() {
+		let server = test_server(ServerConfig {
+			send_id_on_connect: true,
+			..Default::default()
+		});
+		let mut first = TestClient::open(&server, SessionArgs::default()).await;
+		assert_eq!(first.recv().await, "id: 1");
+		let mut second = TestClient::open(&server, SessionArgs::default()).await;
+		assert_eq!(second.recv().await, "id: 2");
+
+		// The snapshot of values comes after the ID
+		let mut snapshot = Vec::new();
+		for _ in 0..3 {
+			snapshot.push(second.recv().await);
+		}
+		snapshot.sort();
+		assert_eq!(snapshot, ["battery: 0", "bpm: 0", "tracker: 0"]);
+
+		let server = test_server(ServerConfig::default());
+		let mut client = TestClient::open(&server, SessionArgs::default()).await;
+		assert!(!client.recv().await.starts_with("id: "));
+	}
+
+	#[tokio::test]
+	async fn snapshot_on_connect_can_be_skipped() {
+		let server = test_server(ServerConfig {
+			skip_snapshot_on_connect: true,
+			..Default::default()
+		});
+		let mut client = TestClient::open(&server, SessionArgs::default()).await;
+		assert_eq!(client.cmd("ping").await, "pong");
+		assert_eq!(client.cmd("sync").await, "sync: version=0 battery=0 bpm=0 tracker=0");
+
+		let server = test_server(ServerConfig::default());
+		let mut client = TestClient::open(&server, SessionArgs::default()).await;
+		assert_ne!(client.cmd("ping").await, "pong");
+	}
+
+	#[tokio::test]
+	async fn acl_token_only_permits_its_keys() {
+		let server = test_server(ServerConfig {
+			acl: HashMap::from([("battery-only".to_owned(), HashSet::from(["battery".to_owned()]))]),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set battery 50").await, "error: not permitted for key");
+		assert_eq!(tracker.cmd("auth nope").await, "error: invalid token");
+		assert_eq!(tracker.cmd("auth battery-only").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 80").await, "error: not permitted for key");
+		assert_eq!(tracker.cmd("set battery 50").await, "ok");
+		assert_eq!(observer.recv().await, "tracker: 1");
+		assert_eq!(observer.recv().await, "battery: 50");
+		assert_eq!(observer.cmd("get battery").await, "battery: 50");
+	}
+
+	#[tokio::test]
+	async fn my_keys_lists_the_keys_a_session_may_set() {
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			acl: HashMap::from([("battery-only".to_owned(), HashSet::from(["battery".to_owned()]))]),
+			..Default::default()
+		});
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(client.cmd("my-keys").await, "my-keys: none");
+		assert_eq!(client.cmd("auth battery-only").await, "ok");
+		assert_eq!(client.cmd("my-keys").await, "my-keys: battery");
+		assert_eq!(client.cmd("auth admin").await, "ok");
+		assert_eq!(client.cmd("my-keys").await, "my-keys: bpm battery");
+
+		// Without any access control tokens, every session may set every key (except read-only ones)
+		let server = test_server(ServerConfig::default());
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(client.cmd("my-keys").await, "my-keys: bpm battery");
+		let args = SessionArgs {
+			readonly: true,
+			..Default::default()
+		};
+		let mut readonly = TestClient::connect_with(&server, args).await;
+		assert_eq!(readonly.cmd("my-keys").await, "my-keys: none");
+	}
+
+	#[tokio::test]
+	async fn opaque_session_ids_still_resolve_for_admin_commands() {
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			opaque_session_ids: true,
+			..Default::default()
+		});
+		let mut admin = TestClient::connect(&server).await;
+		let mut client = TestClient::connect(&server).await;
+		let admin_id = admin.cmd("info").await;
+		let client_id = client.cmd("info").await;
+		let client_id = client_id.strip_prefix("id: ").unwrap().to_owned();
+		assert_eq!(client_id.len(), 16);
+		assert!(client_id.chars().all(|c| c.is_ascii_hexdigit()));
+		assert_ne!(admin_id, format!("id: {client_id}"));
+		assert!(!["1", "2"].contains(&client_id.as_str()));
+
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(admin.cmd("grant-tracker 2 30s").await, "error: unknown session");
+		admin.send(&format!("grant-tracker {client_id} 30s")).await;
+		assert_eq!(admin.recv().await, "tracker: 1");
+		assert_eq!(admin.recv().await, "ok");
+		client.sync().await;
+		assert_eq!(client.cmd("set bpm 80").await, "ok");
+	}
+
+	#[tokio::test]
+	async fn commands_sent_immediately_on_connect_follow_the_initial_values() {
+		let server = test_server(ServerConfig {
+			send_id_on_connect: true,
+			..Default::default()
+		});
+		let mut client = TestClient::open(&server, SessionArgs::default()).await;
+		client.send("info").await;
+		client.send("set bpm 80").await;
+		client.send("get bpm").await;
+		assert_eq!(client.recv().await, "id: 1");
+		let mut snapshot = vec![client.recv().await, client.recv().await, client.recv().await];
+		snapshot.sort();
+		assert_eq!(snapshot, ["battery: 0", "bpm: 0", "tracker: 0"]);
+		for line in ["id: 1", "ok", "bpm: 80"] {
+			assert_eq!(client.recv().await, line);
+		}
+	}
+
+	#[tokio::test]
+	async fn log_level_can_be_raised_at_runtime() {
+		use tracing_subscriber::{layer::SubscriberExt, reload, Layer};
+
+		// Capture log lines at the info level, like the real subscriber does by default
+		let logs = SharedBuffer::default();
+		let writer = logs.clone();
+		let (level, handle) = reload::Layer::new(LevelFilter::INFO);
+		let subscriber = tracing_subscriber::registry().with(
+			tracing_subscriber::fmt::layer()
+				.with_ansi(false)
+				.with_writer(move || writer.clone())
+				.with_filter(level),
+		);
+		let _guard = tracing::subscriber::set_default(subscriber);
+
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			log_level: Some(handle),
+			..Default::default()
+		});
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(client.cmd("set bpm 80").await, "ok");
+		assert!(!logs.contents().contains("changed to \"80\""));
+
+		assert_eq!(client.cmd("log-level debug").await, "error: not authorized");
+		assert_eq!(client.cmd("auth admin").await, "ok");
+		assert_eq!(client.cmd("log-level loud").await, "error: unknown log level");
+		assert_eq!(client.cmd("log-level debug").await, "ok");
+		assert_eq!(client.cmd("set bpm 90").await, "ok");
+		assert!(logs.contents().contains("changed to \"90\""));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn keepalive_lines_reach_idle_sessions_on_interval() {
+		let server = test_server(ServerConfig {
+			keepalive_interval: Some(Duration::from_secs(10)),
+			..Default::default()
+		});
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(client.cmd("ping").await, "pong");
+
+		for _ in 0..2 {
+			tokio::time::advance(Duration::from_secs(10)).await;
+			let line = client.recv().await;
+			let timestamp = line.strip_prefix("keepalive ").unwrap();
+			assert!(timestamp.parse::<u64>().is_ok());
+			assert_eq!(client.cmd("ping").await, "pong");
+		}
+	}
+
+	#[tokio::test]
+	async fn implausible_changes_are_rejected() {
+		let server = test_server(ServerConfig {
+			max_deltas: HashMap::from([("bpm".to_owned(), 40)]),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 180").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 150").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 191").await, "error: implausible change");
+		assert_eq!(tracker.cmd("get bpm").await, "bpm: 150");
+		assert_eq!(tracker.cmd("set bpm 190").await, "ok");
+	}
+
+	#[tokio::test]
+	async fn backups_take_over_in_order_when_the_tracker_leaves() {
+		let server = test_server(ServerConfig::default());
+		let mut primary = TestClient::connect(&server).await;
+		let mut first = TestClient::connect(&server).await;
+		let mut second = TestClient::connect(&server).await;
+		assert_eq!(primary.cmd("set bpm 80").await, "ok");
+		assert_eq!(primary.cmd("claim backup").await, "error: already the tracker");
+		first.sync().await;
+		second.sync().await;
+		assert_eq!(first.cmd("claim backup").await, "ok");
+		assert_eq!(second.cmd("claim backup").await, "ok");
+
+		primary.close().await;
+		assert_eq!(first.recv().await, "promoted");
+		assert_eq!(first.cmd("set bpm 85").await, "ok");
+		assert_eq!(second.recv().await, "bpm: 85");
+		assert_eq!(second.cmd("set bpm 90").await, "error: a tracker is already connected");
+
+		first.close().await;
+		assert_eq!(second.recv().await, "promoted");
+		assert_eq!(second.cmd("set bpm 90").await, "ok");
+	}
+
+	#[tokio::test]
+	async fn sequence_numbers_increase_with_each_change() {
+		let server = test_server(ServerConfig {
+			sequence_numbers: true,
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(observer.recv().await, "tracker: 1 #1");
+		assert_eq!(observer.recv().await, "bpm: 80 #1");
+
+		// Repeating a value isn't a change, so it doesn't use up a number
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 85").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 90").await, "ok");
+		assert_eq!(observer.recv().await, "bpm: 85 #2");
+		assert_eq!(observer.recv().await, "bpm: 90 #3");
+	}
+
+	#[tokio::test]
+	async fn updated_reports_when_a_value_last_changed() {
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("updated bpm").await, "updated bpm: never");
+		assert_eq!(tracker.cmd("updated nope").await, "error: unknown value key");
+
+		let before = unix_millis(SystemTime::now());
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		let after = unix_millis(SystemTime::now());
+		let response = tracker.cmd("updated bpm").await;
+		let updated: u64 = response.strip_prefix("updated bpm: ").unwrap().parse().unwrap();
+		assert!((before..=after).contains(&updated));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn same_tick_changes_are_combined_into_one_line() {
+		let server = test_server(ServerConfig {
+			broadcast_intervals: HashMap::from([
+				("bpm".to_owned(), Duration::from_secs(1)),
+				("battery".to_owned(), Duration::from_secs(1)),
+			]),
+			combine_broadcasts: true,
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(tracker.cmd("set battery 50").await, "ok");
+		assert_eq!(observer.recv().await, "tracker: 1");
+
+		tokio::time::advance(Duration::from_secs(1)).await;
+		assert_eq!(observer.recv().await, "update battery=50 bpm=80");
+
+		// A lone change still gets a line of its own
+		assert_eq!(tracker.cmd("set bpm 85").await, "ok");
+		tokio::time::advance(Duration::from_secs(1)).await;
+		assert_eq!(observer.recv().await, "bpm: 85");
+	}
+
+	#[tokio::test]
+	async fn broadcasts_name_the_session_that_set_the_value() {
+		let server = test_server(ServerConfig {
+			broadcast_source: true,
+			..Default::default()
+		});
+		let mut observer = TestClient::connect(&server).await;
+		let mut first = TestClient::connect(&server).await;
+		assert_eq!(first.cmd("set bpm 80").await, "ok");
+		assert_eq!(observer.recv().await, "tracker: 1 from=2");
+		assert_eq!(observer.recv().await, "bpm: 80 from=2");
+
+		first.close().await;
+		assert_eq!(observer.recv().await, "tracker: 0");
+		let mut second = TestClient::connect(&server).await;
+		assert_eq!(second.cmd("set bpm 90").await, "ok");
+		assert_eq!(observer.recv().await, "tracker: 1 from=3");
+		assert_eq!(observer.recv().await, "bpm: 90 from=3");
+	}
+
+	#[tokio::test]
+	async fn redirect_reaches_every_session() {
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			..Default::default()
+		});
+		let mut admin = TestClient::connect(&server).await;
+		let mut tracker = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		admin.sync().await;
+		observer.sync().await;
+		assert_eq!(admin.cmd("redirect wss://example.com").await, "error: not authorized");
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(
+			admin.cmd("redirect http://example.com").await,
+			"error: invalid websocket URL"
+		);
+		assert_eq!(admin.cmd("redirect ws://").await, "error: invalid websocket URL");
+
+		assert_eq!(
+			admin.cmd("redirect wss://example.com/heartsock").await,
+			"redirect: wss://example.com/heartsock"
+		);
+		assert_eq!(admin.recv().await, "ok");
+		assert_eq!(tracker.recv().await, "redirect: wss://example.com/heartsock");
+		assert_eq!(observer.recv().await, "redirect: wss://example.com/heartsock");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn sets_are_recorded_in_the_audit_log() {
+		let path = test_dir("audit-log").join("audit.jsonl");
+		let server = test_server(ServerConfig {
+			audit_log: Some(path.clone()),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		let mut other = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		other.sync().await;
+		assert_eq!(other.cmd("set bpm 90").await, "error: a tracker is already connected");
+		assert_eq!(tracker.cmd("get bpm").await, "bpm: 80");
+
+		// Records are buffered until the next flush
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+		tokio::time::advance(AUDIT_FLUSH_INTERVAL).await;
+		assert_eq!(tracker.cmd("get bpm").await, "bpm: 80");
+		let contents = std::fs::read_to_string(&path).unwrap();
+		let records: Vec<serde_json::Value> = contents
+			.lines()
+			.map(|line| serde_json::from_str(line).unwrap())
+			.collect();
+		assert_eq!(records.len(), 2);
+		assert_eq!(records[0]["session"], 1);
+		assert_eq!(records[0]["address"], "127.0.0.1:0");
+		assert_eq!(records[0]["command"], "set bpm 80");
+		assert_eq!(records[0]["accepted"], true);
+		assert_eq!(records[1]["session"], 2);
+		assert_eq!(records[1]["command"], "set bpm 90");
+		assert_eq!(records[1]["response"], "error: a tracker is already connected");
+		assert_eq!(records[1]["accepted"], false);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn granted_tracker_role_reverts_after_its_duration() {
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			..Default::default()
+		});
+		let mut admin = TestClient::connect(&server).await;
+		let mut tracker = TestClient::connect(&server).await;
+		let mut granted = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		admin.sync().await;
+		granted.sync().await;
+		assert_eq!(granted.cmd("grant-tracker 3 30s").await, "error: not authorized");
+		assert_eq!(admin.cmd("auth admin").await, "ok");
+		assert_eq!(admin.cmd("grant-tracker 9 30s").await, "error: unknown session");
+		assert_eq!(admin.cmd("grant-tracker 3 30s").await, "ok");
+
+		assert_eq!(granted.cmd("set bpm 90").await, "ok");
+		assert_eq!(tracker.recv().await, "bpm: 90");
+		assert_eq!(tracker.cmd("set bpm 85").await, "error: a tracker is already connected");
+
+		tokio::time::advance(Duration::from_secs(30)).await;
+		assert_eq!(tracker.recv().await, "tracker: 0");
+		assert_eq!(granted.recv().await, "tracker: 0");
+		assert_eq!(tracker.cmd("set bpm 85").await, "ok");
+		assert_eq!(granted.recv().await, "tracker: 1");
+		assert_eq!(granted.recv().await, "bpm: 85");
+		assert_eq!(granted.cmd("set bpm 90").await, "error: a tracker is already connected");
+	}
+
+	#[tokio::test]
+	async fn edits_to_the_watched_file_reach_observers() {
+		let dir = test_dir("watch-file");
+		let path = dir.join("values.txt");
+		let server = test_server(ServerConfig {
+			watch_file: Some(path.clone()),
+			..Default::default()
+		});
+		let mut observer = TestClient::connect(&server).await;
+
+		// Comments, blank lines and invalid lines are skipped
+		std::fs::write(
+			&path,
+			"# Written by the tracker\nBPM = 72\n\nbattery=90\ntracker=1\nbattery=300\n",
+		)
+		.unwrap();
+		assert_eq!(observer.recv().await, "bpm: 72");
+		assert_eq!(observer.recv().await, "battery: 90");
+
+		// Atomically replacing the file is picked up too (modification times can be coarse, so the replacement's is pushed
+		// ahead to make sure it differs)
+		let temp = dir.join("values.tmp");
+		std::fs::write(&temp, "bpm=x\nbpm=80\n").unwrap();
+		let modified = SystemTime::now() + Duration::from_secs(1);
+		std::fs::File::options()
+			.write(true)
+			.open(&temp)
+			.unwrap()
+			.set_modified(modified)
+			.unwrap();
+		std::fs::rename(&temp, &path).unwrap();
+		assert_eq!(observer.recv().await, "bpm: 80");
+		assert_eq!(observer.cmd("get battery").await, "battery: 90");
+	}
+
+	#[tokio::test]
+	async fn subscriptions_reflect_mutes_and_events() {
+		let server = test_server(ServerConfig {
+			admin_token: Some("admin".to_owned()),
+			..Default::default()
+		});
+		let mut client = TestClient::connect(&server).await;
+		assert_eq!(
+			client.cmd("subscriptions").await,
+			"subscriptions: keys=battery,bpm,tracker muted=none events=off"
+		);
+
+		assert_eq!(client.cmd("mute battery").await, "ok");
+		assert_eq!(client.cmd("mute tracker").await, "ok");
+		assert_eq!(client.cmd("auth admin").await, "ok");
+		assert_eq!(client.cmd("events on").await, "ok");
+		assert_eq!(
+			client.cmd("subscriptions").await,
+			"subscriptions: keys=bpm muted=battery,tracker events=on"
+		);
+
+		assert_eq!(client.cmd("unmute battery").await, "ok");
+		assert_eq!(
+			client.cmd("subscriptions").await,
+			"subscriptions: keys=battery,bpm muted=tracker events=on"
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn batched_writes_only_write_the_latest_values() {
+		let data_dir = test_dir("data-write-interval");
+		let server = test_server(ServerConfig {
+			data_dir: Some(data_dir.clone()),
+			data_write_interval: Some(Duration::from_secs(1)),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		let path = data_dir.join("bpm.txt");
+		for bpm in 60..=90 {
+			assert_eq!(tracker.cmd(&format!("set bpm {bpm}")).await, "ok");
+		}
+		assert!(!path.exists());
+
+		tokio::time::advance(Duration::from_secs(1)).await;
+		tracker.sync().await;
+		assert_eq!(fs::read_to_string(&path).unwrap(), "90");
+
+		// Nothing is written while nothing changes
+		let backdated = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+		fs::File::options()
+			.write(true)
+			.open(&path)
+			.unwrap()
+			.set_modified(backdated)
+			.unwrap();
+		tokio::time::advance(Duration::from_secs(1)).await;
+		tracker.sync().await;
+		assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), backdated);
+
+		// Anything left unwritten is written on shutdown
+		for bpm in 91..=99 {
+			assert_eq!(tracker.cmd(&format!("set bpm {bpm}")).await, "ok");
+		}
+		let shutdown = server.call_with(|respond_to| Message::Shutdown { respond_to });
+		let (response, _) = tokio::join!(shutdown, async {
+			tracker.recv_close().await;
+			drop(tracker);
+		});
+		assert_eq!(response, "ok");
+		assert_eq!(fs::read_to_string(&path).unwrap(), "99");
+	}
+
+	#[tokio::test]
+	async fn has_tracker_follows_claims_and_releases() {
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		let mut observer = TestClient::connect(&server).await;
+		assert_eq!(observer.cmd("has-tracker bpm").await, "has-tracker bpm: no");
+		assert_eq!(observer.cmd("has-tracker nope").await, "error: unknown value key");
+		assert_eq!(observer.cmd("has-tracker").await, "error: usage: has-tracker <key>");
+
+		assert_eq!(tracker.cmd("claim").await, "ok");
+		assert_eq!(observer.recv().await, "tracker: 1");
+		assert_eq!(observer.cmd("has-tracker bpm").await, "has-tracker bpm: yes");
+		assert_eq!(observer.cmd("has-tracker battery").await, "has-tracker battery: yes");
+
+		tracker.close().await;
+		assert_eq!(observer.recv().await, "tracker: 0");
+		assert_eq!(observer.cmd("has-tracker bpm").await, "has-tracker bpm: no");
+	}
+
+	#[tokio::test]
+	async fn tracker_hears_when_observers_come_and_go() {
+		let server = test_server(ServerConfig {
+			observer_events: true,
+			..Default::default()
+		});
+		let observer = TestClient::connect(&server).await;
+		let mut tracker = TestClient::connect(&server).await;
+		tracker.send("set bpm 80").await;
+		assert_eq!(tracker.recv().await, "observers-present");
+		assert_eq!(tracker.recv().await, "ok");
+
+		observer.close().await;
+		assert_eq!(tracker.recv().await, "no-observers");
+		let _observer = TestClient::connect(&server).await;
+		assert_eq!(tracker.recv().await, "observers-present");
+	}
+
+	#[tokio::test]
+	async fn capacity_reports_utilization_of_the_session_limit() {
+		let server = test_server(ServerConfig {
+			max_sessions: Some(4),
+			..Default::default()
+		});
+		let mut first = TestClient::connect(&server).await;
+		assert_eq!(
+			first.cmd("capacity").await,
+			"capacity: sessions=1 max=4 utilization=25%"
+		);
+
+		let _second = TestClient::connect(&server).await;
+		let _third = TestClient::connect(&server).await;
+		assert_eq!(
+			first.cmd("capacity").await,
+			"capacity: sessions=3 max=4 utilization=75%"
+		);
+
+		let _fourth = TestClient::connect(&server).await;
+		let mut refused = TestClient::open(&server, SessionArgs::default()).await;
+		assert_eq!(refused.recv().await, "error: server is full");
+		assert_eq!(refused.recv_close().await, "server full");
+		assert_eq!(
+			first.cmd("capacity").await,
+			"capacity: sessions=4 max=4 utilization=100%"
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn origin_rate_limits_are_kept_per_origin() {
+		let config = || ServerConfig {
+			origin_rate_limit: Some(2),
+			..Default::default()
+		};
+		let server = test_server(config());
+		let busy = [("origin", "https://busy.example")];
+		let mut first = TestClient::upgrade(&server, &config(), &busy).await;
+		let mut second = TestClient::upgrade(&server, &config(), &busy).await;
+		let mut quiet = TestClient::upgrade(&server, &config(), &[("origin", "https://quiet.example")]).await;
+
+		// Connecting syncs with a ping, which counts against the limit, so start from a fresh window
+		tokio::time::advance(ORIGIN_WINDOW).await;
+
+		// Sessions from the same origin share its limit
+		assert_eq!(first.cmd("get bpm").await, "bpm: 0");
+		assert_eq!(second.cmd("get bpm").await, "bpm: 0");
+		assert_eq!(first.cmd("get bpm").await, RATE_LIMITED);
+		assert_eq!(second.cmd("get bpm").await, RATE_LIMITED);
+
+		// Other origins (and clients without one) aren't affected
+		assert_eq!(quiet.cmd("get bpm").await, "bpm: 0");
+		assert_eq!(quiet.cmd("get bpm").await, "bpm: 0");
+		let mut native = TestClient::upgrade(&server, &config(), &[]).await;
+		for _ in 0..3 {
+			assert_eq!(native.cmd("get bpm").await, "bpm: 0");
+		}
+
+		tokio::time::advance(ORIGIN_WINDOW).await;
+		assert_eq!(first.cmd("get bpm").await, "bpm: 0");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn state_reports_every_key_with_its_freshness() {
+		let server = test_server(ServerConfig {
+			stale_after: Some(Duration::from_secs(10)),
+			..Default::default()
+		});
+		let mut tracker = TestClient::connect(&server).await;
+		let before = unix_millis(SystemTime::now());
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		tokio::time::advance(Duration::from_secs(11)).await;
+		assert_eq!(tracker.cmd("set battery 50").await, "ok");
+
+		let state = tracker.cmd("state").await;
+		let state: serde_json::Value = serde_json::from_str(state.strip_prefix("state: ").unwrap()).unwrap();
+		let mut keys: Vec<&String> = state.as_object().unwrap().keys().collect();
+		keys.sort();
+		assert_eq!(keys, ["battery", "bpm", "tracker"]);
+		for key in keys {
+			let fields: Vec<&String> = state[key].as_object().unwrap().keys().collect();
+			assert_eq!(fields.len(), 4, "unexpected fields for {key}: {fields:?}");
+			assert!(state[key]["updated_at"].as_u64().unwrap() >= before);
+		}
+		assert_eq!(state["bpm"]["value"], 80);
+		assert_eq!(state["bpm"]["unit"], "BPM");
+		assert_eq!(state["bpm"]["stale"], true);
+		assert_eq!(state["battery"]["value"], 50);
+		assert_eq!(state["battery"]["unit"], "%");
+		assert_eq!(state["battery"]["stale"], false);
+		assert_eq!(state["tracker"]["value"], 1);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn shutdown_gives_up_on_stuck_sessions() {
+		let server = test_server(ServerConfig::default());
+		let mut client = TestClient::connect(&server).await;
+		let (finished, _) = tokio::join!(shut_down(&server, Duration::from_secs(10)), async {
+			client.recv_close().await;
+			drop(client);
+		});
+		assert!(finished);
+
+		// A session that never answers the close keeps the server waiting until the timeout
+		let server = test_server(ServerConfig::default());
+		let _stuck = TestClient::connect(&server).await;
+		let started = tokio::time::Instant::now();
+		assert!(!shut_down(&server, Duration::from_secs(10)).await);
+		assert_eq!(started.elapsed(), Duration::from_secs(10));
+	}
+
+	#[tokio::test]
+	async fn commands_with_unexpected_characters_are_rejected() {
+		let server = test_server(ServerConfig {
+			ascii_commands: true,
+			..Default::default()
+		});
+		let mut client = TestClient::connect(&server).await;
+		for cmd in ["set bpm\0 90", "set\tbpm 90", "get bpm\u{7f}", "get bpé"] {
+			assert_eq!(client.cmd(cmd).await, "error: invalid command encoding");
+		}
+		assert_eq!(client.cmd("get bpm").await, "bpm: 0");
+		// Trailing whitespace like a newline is trimmed before checking
+		assert_eq!(client.cmd("set bpm 80\n").await, "ok");
+
+		let server = test_server(ServerConfig::default());
+		let mut client = TestClient::connect(&server).await;
+		assert_ne!(client.cmd("get\0bpm").await, "error: invalid command encoding");
+	}
+
+	#[tokio::test]
+	async fn change_count_counts_changes_since_start_or_connect() {
+		let server = test_server(ServerConfig::default());
+		let mut tracker = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("change-count bpm").await, "change-count bpm: 0");
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 80").await, "ok");
+		assert_eq!(tracker.cmd("set bpm 85").await, "ok");
+		assert_eq!(tracker.cmd("change-count bpm").await, "change-count bpm: 2");
+
+		let mut late = TestClient::connect(&server).await;
+		assert_eq!(tracker.cmd("set bpm 90").await, "ok");
+		late.sync().await;
+		assert_eq!(late.cmd("change-count bpm").await, "change-count bpm: 3");
+		assert_eq!(late.cmd("change-count bpm since-connect").await, "change-count bpm: 1");
+		assert_eq!(late.cmd("change-count nope").await, "error: unknown value key");
+		assert_eq!(
+			late.cmd("change-count bpm since-forever").await,
+			"error: usage: change-count <key> [since-connect]"
+		);
+	}
+
+	#[tokio::test]
+	async fn handshake_sets_several_options_in_one_frame() {
+		let server = test_server(ServerConfig {
+			skip_snapshot_on_connect: true,
+			..Default::default()
+		});
+		let mut observer = TestClient::open(&server, SessionArgs::default()).await;
+		let handshake = r#"{"handshake": true, "echo": true, "mute": ["battery"], "role": "observer"}"#;
+		assert_eq!(observer.cmd(handshake).await, format!("[{handshake}] ok"));
+		assert_eq!(
+			observer.cmd("subscriptions").await,
+			"[subscriptions] subscriptions: keys=bpm,tracker muted=battery events=off"
+		);
+
+		let mut tracker = TestClient::open(&server, SessionArgs::default()).await;
+		assert_eq!(
+			tracker
+				.cmd(r#"{"handshake": true, "quiet": true, "role": "tracker"}"#)
+				.await,
+			"ok"
+		);
+		assert_eq!(observer.recv().await, "tracker: 1");
+		tracker.send("set battery 50").await;
+		tracker.send("set bpm 80").await;
+		assert_eq!(observer.recv().await, "bpm: 80");
+		assert_eq!(tracker.cmd("ping").await, "pong");
+
+		// Only the first message can be a handshake, and bad options are reported
+		assert_eq!(tracker.cmd(r#"{"handshake": true}"#).await, "error: unknown input");
+		let mut client = TestClient::open(&server, SessionArgs::default()).await;
+		assert_eq!(
+			client.cmd(r#"{"handshake": true, "role": "boss"}"#).await,
+			r#"error: invalid handshake option "role""#
+		);
+		let mut client = TestClient::open(&server, SessionArgs::default()).await;
+		assert_eq!(client.cmd(r#"{"handshake": false}"#).await, "error: unknown input");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn my_limit_reports_the_remaining_allowance() {
+		let server = test_server(ServerConfig {
+			origin_rate_limit: Some(5),
+			..Default::default()
+		});
+		let args = SessionArgs {
+			origin: Some("https://example.com".to_owned()),
+			..Default::default()
+		};
+		let mut client = TestClient::connect_with(&server, args).await;
+		let mut native = TestClient::connect(&server).await;
+		assert_eq!(native.cmd("my-limit").await, "my-limit: none");
+
+		// Checking the limit is a command too, so it counts against it
+		tokio::time::advance(ORIGIN_WINDOW).await;
+		assert_eq!(client.cmd("my-limit").await, "my-limit: limit=5/s remaining=4");
+		assert_eq!(client.cmd("get bpm").await, "bpm: 0");
+		assert_eq!(client.cmd("my-limit").await, "my-limit: limit=5/s remaining=2");
+		assert_eq!(client.cmd("get bpm").await, "bpm: 0");
+		assert_eq!(client.cmd("my-limit").await, "my-limit: limit=5/s remaining=0");
+		assert_eq!(client.cmd("my-limit").await, RATE_LIMITED);
+
+		tokio::time::advance(ORIGIN_WINDOW).await;
+		assert_eq!(client.cmd("my-limit").await, "my-limit: limit=5/s remaining=4");
+	}
 }